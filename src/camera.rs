@@ -1,74 +1,354 @@
-use std::time::Duration;
-
+use bevy::input::mouse::{MouseMotion, MouseScrollUnit, MouseWheel};
 use bevy::prelude::Plugin as BevyPlugin;
 use bevy::prelude::*;
-use bevy_tweening::lens::TransformPositionLens;
-use bevy_tweening::*;
+use bevy::transform::TransformSystem;
+use bevy::utils::HashMap;
+use bevy_egui::EguiContext;
+
+use crate::input::Action;
+use crate::tile_editor::EditorState;
+use crate::tiles::MapBounds;
 
 #[derive(Default, Debug, Component, Clone, Copy)]
 pub struct WorldCamera;
+/// Marks an entity the camera should keep in view
 #[derive(Default, Debug, Component, Clone, Copy)]
-// pub struct CameraFollow(pub Vec3);
-pub struct CameraFollow;
+pub struct CameraTarget;
+
+/// Makes a camera follow every `CameraTarget`, framing all of them when
+/// there are several
+#[derive(Debug, Component, Clone, Copy)]
+pub struct CameraFollow {
+    /// Half-size of the box around the view centre that the target can move
+    /// in without moving the camera
+    pub dead_zone: Vec2,
+    /// How far ahead of a moving target to look, in seconds of its movement
+    pub look_ahead: f32,
+    /// Roughly how long the camera takes to catch up, in seconds
+    pub smoothing: f32,
+    /// A target that moves further than this in one frame has teleported and
+    /// the camera jumps straight to it
+    pub snap_distance: f32,
+    /// Space kept around the targets when framing several of them
+    pub padding: f32,
+    velocity: Vec2,
+}
+
+impl Default for CameraFollow {
+    fn default() -> Self {
+        CameraFollow {
+            dead_zone: Vec2::new(16., 8.),
+            look_ahead: 0.3,
+            smoothing: 0.2,
+            snap_distance: 64.,
+            padding: 32.,
+            velocity: Vec2::ZERO,
+        }
+    }
+}
+
 pub struct Plugin;
 
+/// Zoom and pan behaviour of the world camera. Zoom is the orthographic
+/// projection scale, so smaller values are closer in.
+#[derive(Debug, Clone, Copy)]
+pub struct CameraSettings {
+    pub default_zoom: f32,
+    pub min_zoom: f32,
+    pub max_zoom: f32,
+    /// How much one wheel notch changes the zoom, as a fraction of the
+    /// current zoom
+    pub zoom_speed: f32,
+    /// Edge-scroll speed in screen pixels per second
+    pub pan_speed: f32,
+    /// Distance in pixels from the window edge where edge-scrolling starts
+    pub edge_scroll_margin: f32,
+}
+
+impl Default for CameraSettings {
+    fn default() -> Self {
+        CameraSettings {
+            default_zoom: 0.25,
+            min_zoom: 0.1,
+            max_zoom: 1.,
+            zoom_speed: 0.1,
+            pan_speed: 600.,
+            edge_scroll_margin: 16.,
+        }
+    }
+}
+
 impl BevyPlugin for Plugin {
     fn build(&self, app: &mut App) {
-        app.add_plugin(TweeningPlugin)
+        app.init_resource::<CameraSettings>()
             .add_startup_system(setup)
             .insert_resource(Msaa { samples: 4 })
-            .add_system(follow_character);
+            .add_system(zoom_camera)
+            .add_system_set(SystemSet::on_update(EditorState::Play).with_system(follow_targets))
+            .add_system_set(SystemSet::on_update(EditorState::Edit).with_system(pan_camera))
+            .add_system_to_stage(
+                CoreStage::PostUpdate,
+                clamp_camera.before(TransformSystem::TransformPropagate),
+            );
     }
 }
 
-#[derive(Component)]
-struct Cursor;
-
-pub const SCALE: f32 = 0.25;
-fn setup(mut commands: Commands) {
+fn setup(mut commands: Commands, settings: Res<CameraSettings>) {
     let mut camera_bundle = OrthographicCameraBundle::new_2d();
-    camera_bundle.orthographic_projection.scale = SCALE;
-    commands.spawn_bundle(camera_bundle).insert(WorldCamera);
+    camera_bundle.orthographic_projection.scale = settings.default_zoom;
+    commands
+        .spawn_bundle(camera_bundle)
+        .insert(WorldCamera)
+        .insert(CameraFollow::default());
 }
 
-fn follow_character(
-    mut transforms: Query<&mut Transform>,
-    q_cam: Query<Entity, With<WorldCamera>>,
-    q_follow: Query<Entity, With<CameraFollow>>,
-    mut commands: Commands,
+/// Mouse wheel, two-finger pinch and zoom action zoom
+fn zoom_camera(
+    time: Res<Time>,
+    settings: Res<CameraSettings>,
+    actions: Res<Input<Action>>,
+    mut wheel: EventReader<MouseWheel>,
+    touches: Res<Touches>,
+    mut egui_context: ResMut<EguiContext>,
+    mut query: Query<&mut OrthographicProjection, With<WorldCamera>>,
 ) {
-    if let (Ok(src_e), Ok(flw_e)) = (q_follow.get_single(), q_cam.get_single()) {
-        let mut tr = None;
-        if let Ok(f_t) = transforms.get(src_e) {
-            tr = Some(f_t.translation.clone());
+    let mut factor = 1.;
+
+    // Held zoom actions zoom by this many wheel notches a second
+    const ZOOM_ACTION_RATE: f32 = 10.;
+    for (action, direction) in [(Action::ZoomIn, 1.), (Action::ZoomOut, -1.)] {
+        if actions.pressed(action) {
+            let notches = direction * ZOOM_ACTION_RATE * time.delta_seconds();
+            factor *= (-notches * settings.zoom_speed).exp();
         }
+    }
 
-        if let (Ok(t), Some(tr)) = (transforms.get_mut(flw_e), tr) {
-            let mut new_t = t.translation;
-            new_t.x = tr.x;
-            new_t.y = tr.y;
-            new_t.z = t.translation.z;
-
-            // Create a single animation (tween) to move an entity.
-            let tween = Tween::new(
-                // Use a quadratic easing on both endpoints.
-                EaseFunction::SineInOut,
-                // Loop animation back and forth.
-                TweeningType::Once,
-                // Animation time (one way only; for ping-pong it takes 2 seconds
-                // to come back to start).
-                Duration::from_millis(33),
-                // The lens gives access to the Transform component of the Entity,
-                // for the Animator to animate it. It also contains the start and
-                // end values respectively associated with the progress ratios 0. and 1.
-                TransformPositionLens {
-                    start: t.translation,
-                    end: new_t,
-                },
-            );
+    // Scrolling over UI panels scrolls the panel
+    let over_ui = egui_context.ctx_mut().wants_pointer_input();
+    for event in wheel.iter().filter(|_| !over_ui) {
+        let notches = match event.unit {
+            MouseScrollUnit::Line => event.y,
+            // Roughly one line per 20 pixels of touchpad scrolling
+            MouseScrollUnit::Pixel => event.y / 20.,
+        };
+        factor *= (-notches * settings.zoom_speed).exp();
+    }
+
+    let fingers: Vec<_> = touches.iter().take(3).collect();
+    if let [a, b] = fingers[..] {
+        let previous = a.previous_position().distance(b.previous_position());
+        let current = a.position().distance(b.position());
+        if previous > 0. && current > 0. {
+            factor *= previous / current;
+        }
+    }
+
+    if (factor - 1.).abs() < f32::EPSILON {
+        return;
+    }
+
+    for mut projection in query.iter_mut() {
+        projection.scale = (projection.scale * factor).clamp(settings.min_zoom, settings.max_zoom);
+    }
+}
 
-            let a = Animator::new(tween);
-            commands.entity(flw_e).insert(a);
+/// `Action::Pan` drags and edge-scroll panning while editing
+fn pan_camera(
+    time: Res<Time>,
+    settings: Res<CameraSettings>,
+    windows: Res<Windows>,
+    actions: Res<Input<Action>>,
+    mut motion: EventReader<MouseMotion>,
+    mut egui_context: ResMut<EguiContext>,
+    mut query: Query<(&mut Transform, &OrthographicProjection), With<WorldCamera>>,
+) {
+    let (mut t, projection) = match query.get_single_mut() {
+        Ok(camera) => camera,
+        Err(_) => return,
+    };
+
+    // Screen pixels to move by; motion is y-down, the world is y-up
+    let mut delta = Vec2::ZERO;
+    for event in motion.iter() {
+        if actions.pressed(Action::Pan) {
+            delta += Vec2::new(-event.delta.x, event.delta.y);
         }
     }
+
+    // Panels near the window edge don't scroll the map while in use
+    let over_ui = egui_context.ctx_mut().wants_pointer_input();
+    if let Some(win) = windows.get_primary().filter(|_| !over_ui) {
+        if let Some(cursor) = win.cursor_position() {
+            let margin = settings.edge_scroll_margin;
+            let step = settings.pan_speed * time.delta_seconds();
+            if cursor.x < margin {
+                delta.x -= step;
+            } else if cursor.x > win.width() - margin {
+                delta.x += step;
+            }
+            if cursor.y < margin {
+                delta.y -= step;
+            } else if cursor.y > win.height() - margin {
+                delta.y += step;
+            }
+        }
+    }
+
+    if delta != Vec2::ZERO {
+        t.translation += (delta * projection.scale).extend(0.);
+    }
+}
+
+/// Keeps the view inside the box around the map's diamond, or centred on
+/// the map when zoomed out past it
+fn clamp_camera(
+    bounds: Res<MapBounds>,
+    windows: Res<Windows>,
+    mut query: Query<(&mut Transform, &OrthographicProjection), With<WorldCamera>>,
+) {
+    if bounds.width == 0 || bounds.height == 0 {
+        return;
+    }
+    let win = match windows.get_primary() {
+        Some(win) => win,
+        None => return,
+    };
+
+    // Tile (0, 0) is the top corner; see `utils::iso_to_world`
+    let (w, h) = (bounds.width as f32, bounds.height as f32);
+    let min = Vec2::new(-h * 8., -(w + h - 1.) * 4.);
+    let max = Vec2::new(w * 8., 4.);
+
+    for (mut t, projection) in query.iter_mut() {
+        let half_view = Vec2::new(win.width(), win.height()) / 2. * projection.scale;
+        let p = t.translation.truncate();
+        let clamped = Vec2::new(
+            clamp_axis(p.x, min.x, max.x, half_view.x),
+            clamp_axis(p.y, min.y, max.y, half_view.y),
+        );
+        if clamped != p {
+            t.translation = clamped.extend(t.translation.z);
+        }
+    }
+}
+
+/// Clamps a view centre `p` with half-size `half` to `min..max`
+fn clamp_axis(p: f32, min: f32, max: f32, half: f32) -> f32 {
+    if max - min <= half * 2. {
+        (min + max) / 2.
+    } else {
+        p.clamp(min + half, max - half)
+    }
+}
+
+/// The last position and smoothed velocity of a `CameraTarget`
+#[derive(Default, Debug, Clone, Copy)]
+struct TargetMotion {
+    position: Vec2,
+    velocity: Vec2,
+}
+
+fn follow_targets(
+    time: Res<Time>,
+    settings: Res<CameraSettings>,
+    windows: Res<Windows>,
+    targets: Query<(Entity, &GlobalTransform), (With<CameraTarget>, Without<CameraFollow>)>,
+    mut cameras: Query<(
+        &mut Transform,
+        &mut OrthographicProjection,
+        &mut CameraFollow,
+    )>,
+    mut motions: Local<HashMap<Entity, TargetMotion>>,
+) {
+    let dt = time.delta_seconds();
+    if dt <= 0. {
+        return;
+    }
+
+    let snap_distance = cameras
+        .iter()
+        .map(|(_, _, follow)| follow.snap_distance)
+        .fold(f32::MAX, f32::min);
+    let mut teleported = false;
+    let mut min = Vec2::splat(f32::MAX);
+    let mut max = Vec2::splat(f32::MIN);
+    let mut look_ahead = Vec2::ZERO;
+    let mut count = 0;
+
+    let mut seen = Vec::new();
+    for (e, t) in targets.iter() {
+        let position = t.translation.truncate();
+        let motion = motions.entry(e).or_insert(TargetMotion {
+            position,
+            velocity: Vec2::ZERO,
+        });
+
+        let step = position - motion.position;
+        if step.length() > snap_distance {
+            teleported = true;
+            motion.velocity = Vec2::ZERO;
+        } else {
+            // Movement is stepwise, so smooth it before looking ahead
+            motion.velocity = motion.velocity.lerp(step / dt, (dt * 10.).min(1.));
+        }
+        motion.position = position;
+
+        min = min.min(position);
+        max = max.max(position);
+        look_ahead += motion.velocity;
+        count += 1;
+        seen.push(e);
+    }
+    motions.retain(|e, _| seen.contains(e));
+
+    if count == 0 {
+        return;
+    }
+    let centre = (min + max) / 2.;
+    look_ahead /= count as f32;
+
+    for (mut t, mut projection, mut follow) in cameras.iter_mut() {
+        let current = t.translation.truncate();
+        let focus = centre + look_ahead * follow.look_ahead;
+
+        if teleported {
+            follow.velocity = Vec2::ZERO;
+            t.translation = focus.extend(t.translation.z);
+            continue;
+        }
+
+        // Only move far enough to bring the focus back inside the dead zone
+        let offset = focus - current;
+        let outside = offset - offset.clamp(-follow.dead_zone, follow.dead_zone);
+        let goal = current + outside;
+
+        let mut velocity = follow.velocity;
+        let next = smooth_damp(current, goal, &mut velocity, follow.smoothing, dt);
+        follow.velocity = velocity;
+        t.translation = next.extend(t.translation.z);
+
+        // Ease toward the zoom that just fits several targets, in or out. A
+        // single target keeps whatever zoom the player picked.
+        if count > 1 {
+            if let Some(win) = windows.get_primary() {
+                let needed = (max - min) + Vec2::splat(follow.padding * 2.);
+                let fit = (needed.x / win.width()).max(needed.y / win.height());
+                let fit = fit.clamp(settings.min_zoom, settings.max_zoom);
+                let scale = projection.scale + (fit - projection.scale) * (dt * 5.).min(1.);
+                projection.scale = scale.clamp(settings.min_zoom, settings.max_zoom);
+            }
+        }
+    }
+}
+
+/// Moves `current` toward `target` as a critically damped spring that
+/// settles in about `smoothing` seconds, independent of frame rate
+fn smooth_damp(current: Vec2, target: Vec2, velocity: &mut Vec2, smoothing: f32, dt: f32) -> Vec2 {
+    let omega = 2. / smoothing.max(0.0001);
+    let x = omega * dt;
+    let decay = 1. / (1. + x + 0.48 * x * x + 0.235 * x * x * x);
+    let change = current - target;
+    let temp = (*velocity + change * omega) * dt;
+    *velocity = (*velocity - temp * omega) * decay;
+    target + (change + temp) * decay
 }
@@ -0,0 +1,260 @@
+use std::time::Duration;
+
+use bevy::asset::{AssetLoader, AssetPath, BoxedFuture, LoadContext, LoadedAsset};
+use bevy::prelude::Plugin as BevyPlugin;
+use bevy::prelude::*;
+use bevy::reflect::TypeUuid;
+use bevy::sprite::Rect;
+use bevy::utils::HashMap;
+use serde::Deserialize;
+
+/// The packed sheet with every character's animations
+pub const CHARACTER_SHEET_PATH: &str = "characters/all.json";
+
+/// Sheets are plain JSON. Bevy picks the loader for the longest matching
+/// extension, so `map.json` files still go to their own loader.
+const SHEET_EXTENSION: &str = "json";
+/// Suffix of the sheet that has the same frames before trimming
+const UNTRIMMED_SUFFIX: &str = "_no_trim.json";
+
+/// One frame of a clip
+#[derive(Debug, Clone, Copy)]
+pub struct ClipFrame {
+    /// Index into the sheet's `TextureAtlas`
+    pub index: usize,
+    pub duration: Duration,
+    /// Where the trimmed frame sits relative to the centre of the untrimmed
+    /// frame, y-up
+    pub offset: Vec2,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AnimationClip {
+    pub frames: Vec<ClipFrame>,
+}
+
+impl AnimationClip {
+    pub fn duration(&self) -> Duration {
+        self.frames.iter().map(|f| f.duration).sum()
+    }
+}
+
+/// The clips of one character, by name without the character prefix, e.g.
+/// "idle", "run" or "sword_attack"
+#[derive(Debug, Clone, Default)]
+pub struct Character {
+    pub clips: HashMap<String, AnimationClip>,
+}
+
+impl Character {
+    pub fn clip(&self, name: &str) -> Option<&AnimationClip> {
+        self.clips.get(name)
+    }
+}
+
+/// A packed character atlas and the clips of every character in it, loaded
+/// from a `name.json` description next to `name.png`. The frame sizes
+/// before trimming come from `name_no_trim.json`.
+#[derive(Debug, TypeUuid)]
+#[uuid = "0c5e3a1f-92d4-4b7e-8a6c-3f1d9e2b7a50"]
+pub struct CharacterSheet {
+    pub atlas: Handle<TextureAtlas>,
+    pub characters: HashMap<String, Character>,
+}
+
+impl CharacterSheet {
+    pub fn character(&self, name: &str) -> Option<&Character> {
+        self.characters.get(name)
+    }
+
+    pub fn clip(&self, character: &str, clip: &str) -> Option<&AnimationClip> {
+        self.character(character)?.clip(clip)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct SheetMeta {
+    w: f32,
+    h: f32,
+}
+
+#[derive(Debug, Deserialize)]
+struct ClipData {
+    frames: Vec<FrameData>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FrameData {
+    x: f32,
+    y: f32,
+    w: f32,
+    h: f32,
+    /// Milliseconds
+    duration: u64,
+    /// Position of the trimmed frame in the untrimmed one
+    ox: f32,
+    oy: f32,
+}
+
+#[derive(Default)]
+pub struct CharacterSheetLoader;
+
+impl AssetLoader for CharacterSheetLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let (meta, clips) = parse_sheet(bytes)?;
+
+            let path = load_context.path().to_path_buf();
+            let name = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.strip_suffix(&format!(".{}", SHEET_EXTENSION)))
+                .ok_or_else(|| anyhow::anyhow!("{:?} is not a character sheet", path))?;
+            let untrimmed_bytes = load_context
+                .read_asset_bytes(path.with_file_name(format!("{}{}", name, UNTRIMMED_SUFFIX)))
+                .await?;
+            let (_, untrimmed) = parse_sheet(&untrimmed_bytes)?;
+
+            let image_path = path.with_file_name(format!("{}.png", name));
+            let texture = load_context.get_handle(AssetPath::new(image_path.clone(), None));
+            let mut atlas = TextureAtlas::new_empty(texture, Vec2::new(meta.w, meta.h));
+
+            let mut characters: HashMap<String, Character> = HashMap::default();
+            for (key, data) in clips.iter() {
+                let sizes = untrimmed
+                    .get(key)
+                    .filter(|u| u.frames.len() == data.frames.len())
+                    .ok_or_else(|| anyhow::anyhow!("no untrimmed frames for {}", key))?;
+                let (character, clip) = split_clip_name(key, &clips);
+                characters
+                    .entry(character.to_string())
+                    .or_default()
+                    .clips
+                    .insert(clip.to_string(), build_clip(&mut atlas, data, sizes));
+            }
+
+            let atlas = load_context.set_labeled_asset(
+                "atlas",
+                LoadedAsset::new(atlas).with_dependency(image_path.into()),
+            );
+            load_context.set_default_asset(LoadedAsset::new(CharacterSheet { atlas, characters }));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &[SHEET_EXTENSION]
+    }
+}
+
+/// The atlas size and clips of a sheet
+fn parse_sheet(bytes: &[u8]) -> Result<(SheetMeta, HashMap<String, ClipData>), anyhow::Error> {
+    let mut data: HashMap<String, serde_json::Value> = serde_json::from_slice(bytes)?;
+    let meta: SheetMeta = serde_json::from_value(
+        data.remove("_meta")
+            .ok_or_else(|| anyhow::anyhow!("character sheet has no _meta"))?,
+    )?;
+    let clips = data
+        .into_iter()
+        .map(|(name, value)| Ok((name, serde_json::from_value(value)?)))
+        .collect::<Result<HashMap<String, ClipData>, serde_json::Error>>()?;
+    Ok((meta, clips))
+}
+
+/// Splits a clip key like "knight_blue_idle" into the character and clip
+/// names. The character is the shortest prefix that has an idle clip, so
+/// "basic_sword_attack" belongs to "basic".
+fn split_clip_name<'a, T>(key: &'a str, clips: &HashMap<String, T>) -> (&'a str, &'a str) {
+    key.match_indices('_')
+        .map(|(i, _)| (&key[..i], &key[i + 1..]))
+        .find(|(character, _)| clips.contains_key(&format!("{}_idle", character)))
+        .unwrap_or((key, ""))
+}
+
+/// Adds the frames of `data` to `atlas`. `untrimmed` has the same frames at
+/// their size before trimming.
+fn build_clip(atlas: &mut TextureAtlas, data: &ClipData, untrimmed: &ClipData) -> AnimationClip {
+    let frames = data
+        .frames
+        .iter()
+        .zip(untrimmed.frames.iter())
+        .map(|(f, source)| {
+            let index = atlas.add_texture(Rect {
+                min: Vec2::new(f.x, f.y),
+                max: Vec2::new(f.x + f.w, f.y + f.h),
+            });
+            let centre = Vec2::new(f.ox + f.w / 2., f.oy + f.h / 2.);
+            ClipFrame {
+                index,
+                duration: Duration::from_millis(f.duration),
+                offset: Vec2::new(centre.x - source.w / 2., source.h / 2. - centre.y),
+            }
+        })
+        .collect();
+
+    AnimationClip { frames }
+}
+
+/// Keeps the character sheet loaded
+pub struct CharacterSheets {
+    pub handle: Handle<CharacterSheet>,
+}
+
+pub struct Plugin;
+
+impl BevyPlugin for Plugin {
+    fn build(&self, app: &mut App) {
+        app.add_asset::<CharacterSheet>()
+            .init_asset_loader::<CharacterSheetLoader>()
+            .add_startup_system(load_character_sheet);
+    }
+}
+
+fn load_character_sheet(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands.insert_resource(CharacterSheets {
+        handle: asset_server.load(CHARACTER_SHEET_PATH),
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_sheet(path: &str) -> HashMap<String, ClipData> {
+        let bytes = std::fs::read(std::path::Path::new("assets").join(path)).unwrap();
+        parse_sheet(&bytes).unwrap().1
+    }
+
+    #[test]
+    fn offsets_centre_frames_in_their_untrimmed_size() {
+        let clips = read_sheet(CHARACTER_SHEET_PATH);
+        let untrimmed = read_sheet("characters/all_no_trim.json");
+        let mut atlas = TextureAtlas::new_empty(Handle::default(), Vec2::splat(1024.));
+
+        // An 8x9 frame 4 pixels in from the corner of a 16x16 one
+        let idle = build_clip(&mut atlas, &clips["basic_idle"], &untrimmed["basic_idle"]);
+        assert_eq!(idle.frames[0].offset, Vec2::new(0., -0.5));
+
+        // A 9x9 frame at (1, 6) in a 22x16 one
+        let monk = build_clip(&mut atlas, &clips["monk_idle"], &untrimmed["monk_idle"]);
+        assert_eq!(monk.frames[0].offset, Vec2::new(-5.5, -2.5));
+    }
+
+    #[test]
+    fn every_clip_has_untrimmed_frames() {
+        let clips = read_sheet(CHARACTER_SHEET_PATH);
+        let untrimmed = read_sheet("characters/all_no_trim.json");
+        for (key, data) in clips.iter() {
+            assert_eq!(
+                untrimmed.get(key).map(|u| u.frames.len()),
+                Some(data.frames.len()),
+                "{}",
+                key
+            );
+        }
+    }
+}
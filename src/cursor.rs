@@ -0,0 +1,231 @@
+use bevy::prelude::Plugin as BevyPlugin;
+use bevy::prelude::*;
+use bevy::render::render_resource::{Extent3d, TextureDimension, TextureFormat};
+use bevy::utils::{HashMap, HashSet};
+use bevy_ecs_tilemap::TilePos;
+
+use crate::depth::DEPTH_BASE;
+use crate::input::Action;
+use crate::picking::{tile_to_world, CursorTile, TileClicked};
+use crate::tile_editor::rectangle;
+
+/// Size of the tile outline texture, matching the map's 16×8 grid
+const OUTLINE_SIZE: (u32, u32) = (16, 8);
+/// Outlines draw over the map but under depth-sorted characters and tiles
+const OUTLINE_Z: f32 = DEPTH_BASE - 2.;
+
+const CURSOR_COLOR: Color = Color::rgba(1., 1., 1., 0.8);
+const SELECTION_COLOR: Color = Color::rgb(1., 0.85, 0.2);
+
+/// The outline that follows the tile under the cursor
+#[derive(Component)]
+pub struct Cursor;
+
+/// An outline marking a selected tile
+#[derive(Component)]
+struct SelectionOutline;
+
+/// The selected tiles. Clicking with `Action::Select` held toggles a tile,
+/// dragging adds a box and a plain click or `Action::Cancel` clears the
+/// selection.
+#[derive(Default, Debug, Clone)]
+pub struct TileSelection {
+    pub tiles: HashSet<TilePos>,
+}
+
+impl TileSelection {
+    pub fn contains(&self, pos: TilePos) -> bool {
+        self.tiles.contains(&pos)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tiles.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.tiles.clear();
+    }
+
+    /// Selects `pos` if it isn't, deselects it if it is
+    pub fn toggle(&mut self, pos: TilePos) {
+        if !self.tiles.remove(&pos) {
+            self.tiles.insert(pos);
+        }
+    }
+}
+
+/// The corner a selection drag started on
+#[derive(Default, Debug, Clone, Copy)]
+pub struct SelectionDrag {
+    pub anchor: Option<TilePos>,
+}
+
+struct OutlineTexture(Handle<Image>);
+
+pub struct Plugin;
+
+impl BevyPlugin for Plugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<TileSelection>()
+            .init_resource::<SelectionDrag>()
+            .add_startup_system(setup)
+            .add_system(move_cursor)
+            .add_system(select_tiles)
+            .add_system(draw_selection.after(select_tiles));
+    }
+}
+
+fn setup(mut commands: Commands, mut images: ResMut<Assets<Image>>) {
+    let texture = images.add(diamond_outline(OUTLINE_SIZE.0, OUTLINE_SIZE.1));
+
+    commands
+        .spawn_bundle(SpriteBundle {
+            sprite: Sprite {
+                color: CURSOR_COLOR,
+                ..Default::default()
+            },
+            texture: texture.clone(),
+            visibility: Visibility { is_visible: false },
+            ..Default::default()
+        })
+        .insert(Cursor);
+
+    commands.insert_resource(OutlineTexture(texture));
+}
+
+/// An image with the 2:1 pixel-art outline of one map tile
+fn diamond_outline(width: u32, height: u32) -> Image {
+    let mut image = Image::new_fill(
+        Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        &[0, 0, 0, 0],
+        TextureFormat::Rgba8UnormSrgb,
+    );
+
+    let half_height = height / 2;
+    for x in 0..width {
+        // Distance from the nearest side, which rises one row every two columns
+        let k = x.min(width - 1 - x) / 2;
+        for y in [half_height - 1 - k, half_height + k] {
+            let i = ((y * width + x) * 4) as usize;
+            image.data[i..i + 4].copy_from_slice(&[255, 255, 255, 255]);
+        }
+    }
+    image
+}
+
+/// Snaps the cursor outline to the hovered tile
+fn move_cursor(
+    cursor_tile: Res<CursorTile>,
+    mut query: Query<(&mut Transform, &mut Visibility), With<Cursor>>,
+) {
+    if !cursor_tile.is_changed() {
+        return;
+    }
+
+    for (mut t, mut visibility) in query.iter_mut() {
+        match cursor_tile.current {
+            Some(tp) => {
+                t.translation = tile_to_world(tp).extend(OUTLINE_Z + 1.);
+                visibility.is_visible = true;
+            }
+            None => visibility.is_visible = false,
+        }
+    }
+}
+
+/// Selection follows `Action::Interact` while `Action::Select` is held: a
+/// click toggles a tile and a drag adds a box
+fn select_tiles(
+    mut clicks: EventReader<TileClicked>,
+    actions: Res<Input<Action>>,
+    cursor_tile: Res<CursorTile>,
+    mut drag: ResMut<SelectionDrag>,
+    mut selection: ResMut<TileSelection>,
+) {
+    if actions.just_pressed(Action::Cancel) {
+        drag.anchor = None;
+        if !selection.is_empty() {
+            selection.clear();
+        }
+    }
+
+    for click in clicks.iter() {
+        if click.select {
+            drag.anchor = Some(click.pos);
+        } else if !selection.is_empty() {
+            selection.clear();
+        }
+    }
+
+    let anchor = match drag.anchor {
+        Some(anchor) if !actions.pressed(Action::Interact) => anchor,
+        _ => return,
+    };
+    drag.anchor = None;
+
+    // A release off the map ends the box where the cursor left it
+    let end = cursor_tile
+        .current
+        .or(cursor_tile.previous)
+        .unwrap_or(anchor);
+    if end == anchor {
+        selection.toggle(anchor);
+    } else {
+        selection.tiles.extend(rectangle(anchor, end));
+    }
+}
+
+/// Keeps one outline per selected tile, plus the box being dragged
+fn draw_selection(
+    mut commands: Commands,
+    selection: Res<TileSelection>,
+    drag: Res<SelectionDrag>,
+    cursor_tile: Res<CursorTile>,
+    texture: Option<Res<OutlineTexture>>,
+    mut outlines: Local<HashMap<TilePos, Entity>>,
+) {
+    let texture = match texture {
+        Some(texture) => texture,
+        None => return,
+    };
+    if !selection.is_changed() && !drag.is_changed() && !cursor_tile.is_changed() {
+        return;
+    }
+
+    let mut wanted = selection.tiles.clone();
+    if let (Some(anchor), Some(end)) = (drag.anchor, cursor_tile.current) {
+        wanted.extend(rectangle(anchor, end));
+    }
+
+    outlines.retain(|tp, e| {
+        let keep = wanted.contains(tp);
+        if !keep {
+            commands.entity(*e).despawn();
+        }
+        keep
+    });
+
+    for tp in wanted {
+        if outlines.contains_key(&tp) {
+            continue;
+        }
+        let e = commands
+            .spawn_bundle(SpriteBundle {
+                sprite: Sprite {
+                    color: SELECTION_COLOR,
+                    ..Default::default()
+                },
+                texture: texture.0.clone(),
+                transform: Transform::from_translation(tile_to_world(tp).extend(OUTLINE_Z)),
+                ..Default::default()
+            })
+            .insert(SelectionOutline)
+            .id();
+        outlines.insert(tp, e);
+    }
+}
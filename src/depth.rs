@@ -0,0 +1,59 @@
+use bevy::prelude::Plugin as BevyPlugin;
+use bevy::prelude::*;
+use bevy::transform::TransformSystem;
+
+use crate::movement::MovementSystem;
+use crate::utils::project_iso;
+
+/// Lowest z of depth-sorted sprites, above every map layer
+pub const DEPTH_BASE: f32 = 10.;
+/// z added per tile towards the viewer
+const DEPTH_PER_TILE: f32 = 0.25;
+
+/// Sets an entity's z from where it stands on the iso map, so things nearer
+/// the viewer draw over things behind them
+#[derive(Debug, Component, Clone, Copy, Default)]
+pub struct DepthSort {
+    /// From the translation to the point that touches the ground
+    pub foot_offset: Vec2,
+    /// Breaks ties between things on the same spot
+    pub bias: f32,
+}
+
+impl DepthSort {
+    pub fn with_foot_offset(foot_offset: Vec2) -> Self {
+        DepthSort {
+            foot_offset,
+            ..Default::default()
+        }
+    }
+
+    /// The z of something whose foot is at world position `foot`
+    pub fn depth(foot: Vec2) -> f32 {
+        // Tiles further right and down on the map are nearer the viewer
+        let iso = project_iso(&foot);
+        DEPTH_BASE + (iso.x + iso.y) * DEPTH_PER_TILE
+    }
+}
+
+pub struct Plugin;
+
+impl BevyPlugin for Plugin {
+    fn build(&self, app: &mut App) {
+        app.add_system_to_stage(
+            CoreStage::PostUpdate,
+            depth_sort
+                .after(MovementSystem::Interpolate)
+                .before(TransformSystem::TransformPropagate),
+        );
+    }
+}
+
+fn depth_sort(mut query: Query<(&DepthSort, &mut Transform), Changed<Transform>>) {
+    for (sort, mut t) in query.iter_mut() {
+        let z = DepthSort::depth(t.translation.truncate() + sort.foot_offset) + sort.bias;
+        if t.translation.z != z {
+            t.translation.z = z;
+        }
+    }
+}
@@ -0,0 +1,181 @@
+use bevy::prelude::Plugin as BevyPlugin;
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use bevy_egui::{egui, EguiContext};
+use bevy_tileset_map::prelude::Tileset;
+
+use crate::history::MapEditor;
+use crate::map_file::MapFiles;
+use crate::net::NetMode;
+use crate::tile_editor::{Brush, BrushSettings, EditorState};
+use crate::tiles::{group_texture_index, tile_groups, MapLayer};
+
+/// Size of a palette thumbnail in UI points
+const THUMBNAIL_SIZE: f32 = 36.;
+
+pub struct Plugin;
+
+impl BevyPlugin for Plugin {
+    fn build(&self, app: &mut App) {
+        app.add_system(editor_panel);
+    }
+}
+
+/// The panel for switching between play and edit, and in edit mode saving
+/// the map and picking the brush, layer and tile
+#[allow(clippy::too_many_arguments)]
+fn editor_panel(
+    mut egui_context: ResMut<EguiContext>,
+    mut state: ResMut<State<EditorState>>,
+    mut settings: ResMut<BrushSettings>,
+    tilesets: Res<Assets<Tileset>>,
+    atlases: Res<Assets<TextureAtlas>>,
+    mut textures: Local<HashMap<Handle<Image>, egui::TextureId>>,
+    net_mode: Option<Res<NetMode>>,
+    mut files: MapFiles,
+    mut editor: MapEditor,
+) {
+    // Register tileset textures with egui before borrowing the context
+    for (_, tileset) in tilesets.iter() {
+        let texture = tileset.texture().clone();
+        if !textures.contains_key(&texture) {
+            let id = egui_context.add_image(texture.clone());
+            textures.insert(texture, id);
+        }
+    }
+
+    let mut next_state = None;
+    // Clients play on the server's map
+    let can_edit = net_mode.map_or(true, |mode| mode.can_edit());
+
+    egui::Window::new("Editor")
+        .default_width(200.)
+        .show(egui_context.ctx_mut(), |ui| {
+            let current = *state.current();
+            ui.horizontal(|ui| {
+                for (mode, label) in [(EditorState::Play, "Play"), (EditorState::Edit, "Edit")] {
+                    let enabled = mode == EditorState::Play || can_edit;
+                    let button = egui::SelectableLabel::new(current == mode, label);
+                    if ui.add_enabled(enabled, button).clicked() && current != mode {
+                        next_state = Some(mode);
+                    }
+                }
+            });
+
+            if current != EditorState::Edit {
+                return;
+            }
+
+            ui.separator();
+            ui.horizontal(|ui| {
+                if ui
+                    .add_enabled(editor.history.can_undo(), egui::Button::new("Undo"))
+                    .clicked()
+                {
+                    editor.undo();
+                }
+                if ui
+                    .add_enabled(editor.history.can_redo(), egui::Button::new("Redo"))
+                    .clicked()
+                {
+                    editor.redo();
+                }
+            });
+
+            // The name comes from `--map name`
+            ui.horizontal(|ui| {
+                ui.label(format!("Map: {}", files.name.0));
+                if ui.button("Save").clicked() {
+                    files.save();
+                }
+                if ui.button("Load").clicked() {
+                    files.load();
+                }
+            });
+
+            ui.separator();
+            ui.label("Brush");
+            ui.horizontal_wrapped(|ui| {
+                for (brush, label) in [
+                    (Brush::Single, "Tile"),
+                    (Brush::Rectangle, "Rectangle"),
+                    (Brush::Line, "Line"),
+                    (Brush::Fill, "Fill"),
+                ] {
+                    ui.selectable_value(&mut settings.brush, brush, label);
+                }
+            });
+
+            ui.label("Layer");
+            ui.horizontal_wrapped(|ui| {
+                for (layer, label) in [
+                    (MapLayer::Ground, "Ground"),
+                    (MapLayer::Decoration, "Decoration"),
+                    (MapLayer::Obstacles, "Obstacles"),
+                ] {
+                    ui.selectable_value(&mut settings.layer, layer, label);
+                }
+            });
+
+            ui.separator();
+            if ui
+                .selectable_label(settings.tile.is_none(), "Eraser")
+                .clicked()
+            {
+                settings.tile = None;
+            }
+
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                for (_, tileset) in tilesets.iter() {
+                    let texture_id = match textures.get(tileset.texture()) {
+                        Some(id) => *id,
+                        None => continue,
+                    };
+
+                    ui.heading(tileset.name());
+                    ui.horizontal_wrapped(|ui| {
+                        for (_, group) in tile_groups(tileset) {
+                            let selected = settings.tileset == tileset.name()
+                                && settings.tile.as_deref() == Some(group);
+                            let button = match thumbnail_uv(tileset, &atlases, group) {
+                                Some(uv) => egui::ImageButton::new(
+                                    texture_id,
+                                    egui::vec2(THUMBNAIL_SIZE, THUMBNAIL_SIZE),
+                                )
+                                .uv(uv)
+                                .selected(selected),
+                                None => continue,
+                            };
+
+                            if ui.add(button).on_hover_text(group).clicked() {
+                                settings.tileset = tileset.name().to_string();
+                                settings.tile = Some(group.to_string());
+                            }
+                        }
+                    });
+                }
+            });
+        });
+
+    if let Some(mode) = next_state {
+        state.set(mode).ok();
+    }
+}
+
+/// The part of the tileset texture showing the first frame of `group`
+fn thumbnail_uv(
+    tileset: &Tileset,
+    atlases: &Assets<TextureAtlas>,
+    group: &str,
+) -> Option<egui::Rect> {
+    let atlas = atlases.get(tileset.atlas())?;
+    let rect = atlas
+        .textures
+        .get(group_texture_index(tileset, group)? as usize)?;
+    let size = atlas.size;
+
+    Some(egui::Rect::from_min_max(
+        egui::pos2(rect.min.x / size.x, rect.min.y / size.y),
+        egui::pos2(rect.max.x / size.x, rect.max.y / size.y),
+    ))
+}
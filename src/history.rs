@@ -0,0 +1,659 @@
+use std::collections::VecDeque;
+
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::Plugin as BevyPlugin;
+use bevy::prelude::*;
+use bevy::utils::{HashMap, HashSet};
+use bevy_ecs_tilemap::{Tile, TileParent, TilePos};
+use bevy_tileset_map::prelude::{TileId, TilePlacer, Tilesets};
+
+use crate::input::Action;
+use crate::tile_editor::EditorState;
+use crate::tiles::{MapLayer, MAP_ID};
+
+/// How many steps `EditHistory` keeps by default
+pub const DEFAULT_HISTORY_LIMIT: usize = 100;
+
+pub struct Plugin;
+
+impl BevyPlugin for Plugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(EditHistory::new(DEFAULT_HISTORY_LIMIT))
+            .init_resource::<AutoTileWatch>()
+            .add_system_set(SystemSet::on_update(EditorState::Edit).with_system(undo_redo))
+            // Auto tiles are updated in the tileset and tilemap stages
+            .add_system_to_stage(CoreStage::PostUpdate, record_auto_tiles);
+    }
+}
+
+/// A single reversible change to the map
+#[derive(Debug, Clone, PartialEq)]
+pub enum EditOp {
+    /// A tile was placed, replaced or removed
+    Tile {
+        pos: TilePos,
+        layer: MapLayer,
+        before: Option<TileId>,
+        after: Option<TileId>,
+    },
+    /// The contents of a whole layer were replaced
+    Layer {
+        layer: MapLayer,
+        before: Vec<(TilePos, TileId)>,
+        after: Vec<(TilePos, TileId)>,
+    },
+}
+
+impl EditOp {
+    /// The op that undoes this one
+    pub fn inverse(&self) -> EditOp {
+        match self.clone() {
+            EditOp::Tile {
+                pos,
+                layer,
+                before,
+                after,
+            } => EditOp::Tile {
+                pos,
+                layer,
+                before: after,
+                after: before,
+            },
+            EditOp::Layer {
+                layer,
+                before,
+                after,
+            } => EditOp::Layer {
+                layer,
+                before: after,
+                after: before,
+            },
+        }
+    }
+}
+
+/// Ops that are undone and redone together
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EditStep {
+    pub ops: Vec<EditOp>,
+}
+
+impl EditStep {
+    /// The step that undoes this one
+    pub fn inverse(&self) -> EditStep {
+        EditStep {
+            ops: self.ops.iter().rev().map(EditOp::inverse).collect(),
+        }
+    }
+}
+
+/// Undo and redo stacks of map edits.
+///
+/// Ops recorded between `begin_stroke` and `end_stroke` are merged into a
+/// single step. Once `limit` steps are stored the oldest are dropped.
+#[derive(Debug, Clone)]
+pub struct EditHistory {
+    undo: VecDeque<EditStep>,
+    redo: Vec<EditStep>,
+    stroke: Option<EditStep>,
+    limit: usize,
+}
+
+impl EditHistory {
+    pub fn new(limit: usize) -> Self {
+        EditHistory {
+            undo: VecDeque::new(),
+            redo: Vec::new(),
+            stroke: None,
+            limit,
+        }
+    }
+
+    pub fn record(&mut self, op: EditOp) {
+        match self.stroke.as_mut() {
+            Some(stroke) => stroke.ops.push(op),
+            None => self.push(EditStep { ops: vec![op] }),
+        }
+    }
+
+    pub fn begin_stroke(&mut self) {
+        self.end_stroke();
+        self.stroke = Some(EditStep::default());
+    }
+
+    pub fn end_stroke(&mut self) {
+        if let Some(stroke) = self.stroke.take() {
+            if !stroke.ops.is_empty() {
+                self.push(stroke);
+            }
+        }
+    }
+
+    fn push(&mut self, step: EditStep) {
+        self.redo.clear();
+        self.undo.push_back(step);
+        while self.undo.len() > self.limit {
+            self.undo.pop_front();
+        }
+    }
+
+    /// Moves the latest step to the redo stack and returns the step that
+    /// reverts it
+    pub fn undo(&mut self) -> Option<EditStep> {
+        self.end_stroke();
+        let step = self.undo.pop_back()?;
+        let inverse = step.inverse();
+        self.redo.push(step);
+        Some(inverse)
+    }
+
+    /// Moves the latest undone step back and returns it to be applied again
+    pub fn redo(&mut self) -> Option<EditStep> {
+        self.end_stroke();
+        let step = self.redo.pop()?;
+        self.undo.push_back(step.clone());
+        Some(step)
+    }
+
+    /// Adds ops to the step being recorded, or to the latest step if no
+    /// stroke is open
+    pub fn amend(&mut self, ops: Vec<EditOp>) {
+        if ops.is_empty() {
+            return;
+        }
+        if let Some(stroke) = self.stroke.as_mut() {
+            stroke.ops.extend(ops);
+        } else if let Some(step) = self.undo.back_mut() {
+            step.ops.extend(ops);
+        } else {
+            self.push(EditStep { ops });
+        }
+    }
+
+    /// Forgets every step, e.g. when another map is loaded
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+        self.stroke = None;
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty() || self.stroke.as_ref().map_or(false, |s| !s.ops.is_empty())
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    /// Recorded steps, oldest first
+    pub fn steps(&self) -> impl Iterator<Item = &EditStep> {
+        self.undo.iter()
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+        while self.undo.len() > self.limit {
+            self.undo.pop_front();
+        }
+    }
+}
+
+/// Tiles next to painted ones, as they were before painting. The auto tiler
+/// changes them later in the frame, after which `record_auto_tiles` adds what
+/// changed to the history.
+#[derive(Debug, Default)]
+pub struct AutoTileWatch {
+    layers: HashMap<MapLayer, (String, HashMap<TilePos, Option<TileId>>)>,
+}
+
+/// Edits the map through `EditHistory` so every change can be undone
+#[derive(SystemParam)]
+pub struct MapEditor<'w, 's> {
+    placer: TilePlacer<'w, 's>,
+    tiles: Query<'w, 's, (&'static TilePos, &'static Tile, &'static TileParent)>,
+    tilesets: Tilesets<'w, 's>,
+    watch: ResMut<'w, AutoTileWatch>,
+    pub history: ResMut<'w, EditHistory>,
+}
+
+impl<'w, 's> MapEditor<'w, 's> {
+    /// The id of a tile group in the named tileset
+    pub fn tile_id(&self, tileset: &str, group: &str) -> Option<TileId> {
+        let tileset = self.tilesets.get_by_name(tileset)?;
+        tileset
+            .get_tile_group_id(group)
+            .map(|id| TileId::new(*id, tileset.id().clone()))
+    }
+
+    /// The tiles currently on `layer`, which is drawn with the named tileset
+    pub fn layer_tiles(&self, tileset: &str, layer: MapLayer) -> HashMap<TilePos, TileId> {
+        self.tiles_where(tileset, layer, |_| true)
+    }
+
+    /// The tiles on `layer` at positions `keep` accepts. Only those tiles
+    /// have their id resolved.
+    fn tiles_where(
+        &self,
+        tileset: &str,
+        layer: MapLayer,
+        mut keep: impl FnMut(&TilePos) -> bool,
+    ) -> HashMap<TilePos, TileId> {
+        let tileset = match self.tilesets.get_by_name(tileset) {
+            Some(tileset) => tileset,
+            None => return HashMap::default(),
+        };
+
+        // Every frame of a group resolves, including its variants and auto
+        // tile rules
+        self.tiles
+            .iter()
+            .filter(|(tp, _, p)| p.map_id == MAP_ID && p.layer_id == layer.id() && keep(tp))
+            .filter_map(|(tp, tile, _)| {
+                tileset
+                    .get_tile_id(&(tile.texture_index as usize))
+                    .map(|id| (*tp, *id))
+            })
+            .collect()
+    }
+
+    /// Sets every tile in `positions` on `layer` to `tile`, or removes them
+    /// if it is `None`. `tileset` is the one the layer is drawn with.
+    pub fn paint(
+        &mut self,
+        tileset: &str,
+        layer: MapLayer,
+        positions: &[TilePos],
+        tile: Option<TileId>,
+    ) {
+        if positions.is_empty() {
+            return;
+        }
+        let touched: HashSet<TilePos> = positions.iter().copied().collect();
+        let around = surrounding(&touched);
+        let current = self.tiles_where(tileset, layer, |tp| {
+            touched.contains(tp) || around.contains(tp)
+        });
+
+        // Auto tiles around the painted ones may change to match them
+        let (_, watched) = self
+            .watch
+            .layers
+            .entry(layer)
+            .or_insert_with(|| (tileset.to_string(), HashMap::default()));
+        for pos in around {
+            watched
+                .entry(pos)
+                .or_insert_with(|| current.get(&pos).copied());
+        }
+        for pos in touched.iter() {
+            watched.remove(pos);
+        }
+
+        for pos in positions {
+            let before = current.get(pos).copied();
+            if same_group(before, tile) {
+                continue;
+            }
+
+            let op = EditOp::Tile {
+                pos: *pos,
+                layer,
+                before,
+                after: tile,
+            };
+            self.apply(&op);
+            self.history.record(op);
+        }
+    }
+
+    /// Removes every tile on `layer`
+    pub fn clear_layer(&mut self, tileset: &str, layer: MapLayer) {
+        let before: Vec<_> = self.layer_tiles(tileset, layer).into_iter().collect();
+        if before.is_empty() {
+            return;
+        }
+
+        let op = EditOp::Layer {
+            layer,
+            before,
+            after: Vec::new(),
+        };
+        self.apply(&op);
+        self.history.record(op);
+    }
+
+    pub fn begin_stroke(&mut self) {
+        self.history.begin_stroke();
+    }
+
+    pub fn end_stroke(&mut self) {
+        self.history.end_stroke();
+    }
+
+    pub fn undo(&mut self) -> bool {
+        match self.history.undo() {
+            Some(step) => {
+                self.apply_step(&step);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn redo(&mut self) -> bool {
+        match self.history.redo() {
+            Some(step) => {
+                self.apply_step(&step);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Applies a step without recording it, e.g. to replay a history
+    pub fn apply_step(&mut self, step: &EditStep) {
+        for op in step.ops.iter() {
+            self.apply(op);
+        }
+    }
+
+    fn apply(&mut self, op: &EditOp) {
+        match op {
+            EditOp::Tile {
+                pos, layer, after, ..
+            } => self.set_tile(*pos, *layer, *after),
+            EditOp::Layer {
+                layer,
+                before,
+                after,
+            } => {
+                for (pos, _) in before.iter() {
+                    self.set_tile(*pos, *layer, None);
+                }
+                for (pos, id) in after.iter() {
+                    self.set_tile(*pos, *layer, Some(*id));
+                }
+            }
+        }
+    }
+
+    /// Records the auto tiles that changed around this frame's paints
+    fn record_auto_tiles(&mut self) {
+        let watched = std::mem::take(&mut self.watch.layers);
+        for (layer, (tileset, before)) in watched {
+            let now = self.tiles_where(&tileset, layer, |tp| before.contains_key(tp));
+            let ops = auto_tile_ops(layer, &before, &now);
+            self.history.amend(ops);
+        }
+    }
+
+    fn set_tile(&mut self, pos: TilePos, layer: MapLayer, tile: Option<TileId>) {
+        // Places the exact variant or auto tile, even over one of its group
+        if let Some(id) = tile {
+            self.placer.place(id, pos, MAP_ID, layer.id()).err();
+        } else {
+            self.placer.remove(pos, MAP_ID, layer.id()).err();
+        }
+    }
+}
+
+/// Whether both are empty or tiles of the same group
+fn same_group(a: Option<TileId>, b: Option<TileId>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => a.eq_tile_group(&b),
+        (a, b) => a.is_none() && b.is_none(),
+    }
+}
+
+/// The tiles next to `touched` that are not in it
+fn surrounding(touched: &HashSet<TilePos>) -> HashSet<TilePos> {
+    touched
+        .iter()
+        .flat_map(|pos| {
+            let (x, y) = (pos.0 as i64, pos.1 as i64);
+            (-1..=1).flat_map(move |dy| (-1..=1).map(move |dx| (x + dx, y + dy)))
+        })
+        .filter(|&(x, y)| x >= 0 && y >= 0)
+        .map(|(x, y)| TilePos(x as u32, y as u32))
+        .filter(|pos| !touched.contains(pos))
+        .collect()
+}
+
+/// Ops for the tiles in `before` that are different `now`
+fn auto_tile_ops(
+    layer: MapLayer,
+    before: &HashMap<TilePos, Option<TileId>>,
+    now: &HashMap<TilePos, TileId>,
+) -> Vec<EditOp> {
+    let mut positions: Vec<_> = before.keys().copied().collect();
+    positions.sort_by_key(|pos| (pos.1, pos.0));
+    positions
+        .into_iter()
+        .filter_map(|pos| {
+            let (before, after) = (before[&pos], now.get(&pos).copied());
+            (before != after).then(|| EditOp::Tile {
+                pos,
+                layer,
+                before,
+                after,
+            })
+        })
+        .collect()
+}
+
+fn record_auto_tiles(mut editor: MapEditor) {
+    editor.record_auto_tiles();
+}
+
+/// `Action::Undo` and `Action::Redo` step through the history
+fn undo_redo(actions: Res<Input<Action>>, mut editor: MapEditor) {
+    if actions.just_pressed(Action::Undo) {
+        editor.undo();
+    } else if actions.just_pressed(Action::Redo) {
+        editor.redo();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tile(group: u32) -> Option<TileId> {
+        Some(TileId::new(group, 0))
+    }
+
+    fn auto_tile(group: u32, rule: usize) -> Option<TileId> {
+        let mut id = TileId::new(group, 0);
+        id.auto_index = Some(rule);
+        Some(id)
+    }
+
+    fn variant(group: u32, variant: usize) -> Option<TileId> {
+        let mut id = TileId::new(group, 0);
+        id.variant_index = Some(variant);
+        Some(id)
+    }
+
+    fn paint(pos: u32, before: Option<TileId>, after: Option<TileId>) -> EditOp {
+        EditOp::Tile {
+            pos: TilePos(pos, 0),
+            layer: MapLayer::Ground,
+            before,
+            after,
+        }
+    }
+
+    /// Plays ops onto a plain map, the way `MapEditor::apply` does
+    fn replay(map: &mut HashMap<TilePos, TileId>, step: &EditStep) {
+        for op in step.ops.iter() {
+            match op {
+                EditOp::Tile { pos, after, .. } => match after {
+                    Some(id) => {
+                        map.insert(*pos, *id);
+                    }
+                    None => {
+                        map.remove(pos);
+                    }
+                },
+                EditOp::Layer { before, after, .. } => {
+                    for (pos, _) in before.iter() {
+                        map.remove(pos);
+                    }
+                    map.extend(after.iter().copied());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn stroke_ops_merge_into_one_step() {
+        let mut history = EditHistory::new(DEFAULT_HISTORY_LIMIT);
+        history.begin_stroke();
+        history.record(paint(0, None, tile(1)));
+        history.record(paint(1, None, tile(1)));
+        assert!(history.can_undo());
+        history.end_stroke();
+        history.record(paint(2, None, tile(2)));
+
+        let steps: Vec<_> = history.steps().map(|s| s.ops.len()).collect();
+        assert_eq!(steps, [2, 1]);
+
+        // An empty stroke leaves no step behind
+        history.begin_stroke();
+        history.end_stroke();
+        assert_eq!(history.steps().count(), 2);
+    }
+
+    #[test]
+    fn oldest_steps_are_dropped_past_the_limit() {
+        let mut history = EditHistory::new(DEFAULT_HISTORY_LIMIT);
+        for i in 0..DEFAULT_HISTORY_LIMIT as u32 + 5 {
+            history.record(paint(i, None, tile(1)));
+        }
+        assert_eq!(history.steps().count(), DEFAULT_HISTORY_LIMIT);
+        assert_eq!(
+            history.steps().next(),
+            Some(&EditStep {
+                ops: vec![paint(5, None, tile(1))]
+            })
+        );
+
+        history.set_limit(10);
+        assert_eq!(history.steps().count(), 10);
+    }
+
+    #[test]
+    fn undo_and_redo_replay_the_map() {
+        let mut history = EditHistory::new(DEFAULT_HISTORY_LIMIT);
+        let mut map = HashMap::default();
+        fn edit(history: &mut EditHistory, map: &mut HashMap<TilePos, TileId>, op: EditOp) {
+            let step = EditStep {
+                ops: vec![op.clone()],
+            };
+            replay(map, &step);
+            history.record(op);
+        }
+
+        history.begin_stroke();
+        edit(&mut history, &mut map, paint(0, None, tile(1)));
+        edit(&mut history, &mut map, paint(0, tile(1), tile(2)));
+        history.end_stroke();
+        let painted = map.clone();
+        let clear = EditOp::Layer {
+            layer: MapLayer::Ground,
+            before: map.iter().map(|(p, t)| (*p, *t)).collect(),
+            after: Vec::new(),
+        };
+        edit(&mut history, &mut map, clear);
+        assert!(map.is_empty());
+
+        replay(&mut map, &history.undo().expect("undo clear"));
+        assert_eq!(map, painted);
+        replay(&mut map, &history.undo().expect("undo stroke"));
+        assert!(map.is_empty());
+        assert!(history.undo().is_none());
+
+        replay(&mut map, &history.redo().expect("redo stroke"));
+        assert_eq!(map, painted);
+
+        // A new edit drops what was left to redo
+        edit(&mut history, &mut map, paint(3, None, tile(1)));
+        assert!(!history.can_redo());
+        assert!(history.redo().is_none());
+    }
+
+    #[test]
+    fn painting_keeps_variants_of_the_same_group() {
+        assert!(same_group(variant(1, 2), tile(1)));
+        assert!(same_group(auto_tile(1, 4), auto_tile(1, 0)));
+        assert!(same_group(None, None));
+        assert!(!same_group(variant(1, 2), tile(2)));
+        assert!(!same_group(None, tile(1)));
+    }
+
+    #[test]
+    fn undoing_a_paint_over_auto_tiles_restores_them() {
+        let mut history = EditHistory::new(DEFAULT_HISTORY_LIMIT);
+        // A row of auto tiles above a variant tile
+        let mut map: HashMap<TilePos, TileId> = [
+            (TilePos(0, 0), auto_tile(1, 3)),
+            (TilePos(1, 0), auto_tile(1, 7)),
+            (TilePos(2, 0), auto_tile(1, 5)),
+            (TilePos(1, 1), variant(2, 2)),
+        ]
+        .into_iter()
+        .map(|(pos, id)| (pos, id.unwrap()))
+        .collect();
+        let original = map.clone();
+
+        // Paint over the middle of the row and the variant below it
+        let touched = [TilePos(1, 0), TilePos(1, 1)].into_iter().collect();
+        let around: HashMap<_, _> = surrounding(&touched)
+            .into_iter()
+            .map(|pos| (pos, map.get(&pos).copied()))
+            .collect();
+        history.begin_stroke();
+        for op in [
+            paint(1, auto_tile(1, 7), tile(3)),
+            EditOp::Tile {
+                pos: TilePos(1, 1),
+                layer: MapLayer::Ground,
+                before: variant(2, 2),
+                after: tile(3),
+            },
+        ] {
+            replay(
+                &mut map,
+                &EditStep {
+                    ops: vec![op.clone()],
+                },
+            );
+            history.record(op);
+        }
+        history.end_stroke();
+
+        // The auto tiler then gives the row's ends their single tile rule
+        map.insert(TilePos(0, 0), auto_tile(1, 0).unwrap());
+        map.insert(TilePos(2, 0), auto_tile(1, 0).unwrap());
+        let ops = auto_tile_ops(MapLayer::Ground, &around, &map);
+        assert_eq!(
+            ops,
+            [
+                paint(0, auto_tile(1, 3), auto_tile(1, 0)),
+                paint(2, auto_tile(1, 5), auto_tile(1, 0)),
+            ]
+        );
+        history.amend(ops);
+        assert_eq!(history.steps().count(), 1);
+        let painted = map.clone();
+
+        replay(&mut map, &history.undo().expect("undo paint"));
+        assert_eq!(map, original);
+        replay(&mut map, &history.redo().expect("redo paint"));
+        assert_eq!(map, painted);
+    }
+}
@@ -0,0 +1,491 @@
+use bevy::asset::{AssetLoader, BoxedFuture, LoadContext, LoadedAsset};
+use bevy::input::InputSystem;
+use bevy::prelude::Plugin as BevyPlugin;
+use bevy::prelude::*;
+use bevy::reflect::TypeUuid;
+use bevy::utils::{HashMap, HashSet};
+use serde::{Deserialize, Serialize};
+
+use crate::picking::Modifiers;
+use crate::sprite::Facing;
+
+/// Where the default bindings are loaded from
+pub const INPUT_MAP_PATH: &str = "config/default.input.ron";
+
+/// How far a gamepad axis has to move before it counts as pressed
+const AXIS_THRESHOLD: f32 = 0.5;
+
+/// Things the player can do, independent of the device they do it with.
+/// Systems read these from `Res<Input<Action>>`, so tests can press them
+/// directly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Action {
+    MoveNorth,
+    MoveNorthEast,
+    MoveEast,
+    MoveSouthEast,
+    MoveSouth,
+    MoveSouthWest,
+    MoveWest,
+    MoveNorthWest,
+    /// Act on the hovered tile, e.g. walk to it
+    Interact,
+    /// Stop what is going on, e.g. walking or a selection
+    Cancel,
+    /// Held to make `Interact` select tiles instead of acting on them
+    Select,
+    Jump,
+    ToggleEditor,
+    /// Paint with the active brush while held
+    EditorPaint,
+    ZoomIn,
+    ZoomOut,
+    /// Drag the camera while held
+    Pan,
+    BrushSingle,
+    BrushRectangle,
+    BrushLine,
+    BrushFill,
+    /// Switch between the eraser and the default tile
+    ToggleEraser,
+    /// Remove every tile on the active layer
+    ClearLayer,
+    Undo,
+    Redo,
+    SaveMap,
+    LoadMap,
+}
+
+impl Action {
+    /// The movement actions and the screen direction of each
+    pub const MOVES: [(Action, Facing); 8] = [
+        (Action::MoveNorth, Facing::North),
+        (Action::MoveNorthEast, Facing::NorthEast),
+        (Action::MoveEast, Facing::East),
+        (Action::MoveSouthEast, Facing::SouthEast),
+        (Action::MoveSouth, Facing::South),
+        (Action::MoveSouthWest, Facing::SouthWest),
+        (Action::MoveWest, Facing::West),
+        (Action::MoveNorthWest, Facing::NorthWest),
+    ];
+}
+
+/// A key, button or stick direction an action can be bound to. Gamepad
+/// bindings match any connected gamepad.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Binding {
+    /// A key, whatever modifiers are held with it
+    Key(KeyCode),
+    /// A key held with exactly these modifiers, e.g. Ctrl+Z
+    Chord {
+        key: KeyCode,
+        #[serde(default)]
+        ctrl: bool,
+        #[serde(default)]
+        shift: bool,
+        #[serde(default)]
+        alt: bool,
+    },
+    Mouse(MouseButton),
+    Button(GamepadButtonType),
+    Axis {
+        axis: GamepadAxisType,
+        /// Whether the axis is pushed towards positive or negative
+        positive: bool,
+    },
+}
+
+/// The bindings of every action. Edit the resource to rebind at runtime.
+#[derive(Debug, Clone, Serialize, Deserialize, TypeUuid)]
+#[uuid = "7b2d9c4e-1a6f-4f38-9e05-c8a3b6d1f274"]
+pub struct InputMap {
+    pub bindings: HashMap<Action, Vec<Binding>>,
+}
+
+impl InputMap {
+    pub fn bindings(&self, action: Action) -> &[Binding] {
+        self.bindings.get(&action).map_or(&[], Vec::as_slice)
+    }
+
+    /// Adds `binding` to `action`, keeping its other bindings
+    pub fn bind(&mut self, action: Action, binding: Binding) {
+        let bindings = self.bindings.entry(action).or_default();
+        if !bindings.contains(&binding) {
+            bindings.push(binding);
+        }
+    }
+
+    /// Replaces every binding of `action` with `binding`
+    pub fn rebind(&mut self, action: Action, binding: Binding) {
+        self.bindings.insert(action, vec![binding]);
+    }
+
+    pub fn unbind(&mut self, action: Action, binding: Binding) {
+        if let Some(bindings) = self.bindings.get_mut(&action) {
+            bindings.retain(|b| *b != binding);
+        }
+    }
+}
+
+impl Default for InputMap {
+    fn default() -> Self {
+        use Action::*;
+        use Binding::*;
+
+        let stick = |axis, positive| Axis { axis, positive };
+        let chord = |key, ctrl, shift| Chord {
+            key,
+            ctrl,
+            shift,
+            alt: false,
+        };
+        let bindings = [
+            (
+                MoveNorth,
+                vec![Key(KeyCode::W), stick(GamepadAxisType::LeftStickY, true)],
+            ),
+            (MoveNorthEast, vec![Key(KeyCode::E)]),
+            (
+                MoveEast,
+                vec![Key(KeyCode::D), stick(GamepadAxisType::LeftStickX, true)],
+            ),
+            (MoveSouthEast, vec![Key(KeyCode::C)]),
+            (
+                MoveSouth,
+                vec![Key(KeyCode::X), stick(GamepadAxisType::LeftStickY, false)],
+            ),
+            (MoveSouthWest, vec![Key(KeyCode::Z)]),
+            (
+                MoveWest,
+                vec![Key(KeyCode::A), stick(GamepadAxisType::LeftStickX, false)],
+            ),
+            (MoveNorthWest, vec![Key(KeyCode::Q)]),
+            (
+                Interact,
+                vec![Mouse(MouseButton::Left), Button(GamepadButtonType::South)],
+            ),
+            (
+                Cancel,
+                vec![Key(KeyCode::Escape), Button(GamepadButtonType::East)],
+            ),
+            (Select, vec![Key(KeyCode::LShift), Key(KeyCode::RShift)]),
+            (
+                Jump,
+                vec![Key(KeyCode::Space), Button(GamepadButtonType::North)],
+            ),
+            (
+                ToggleEditor,
+                vec![Key(KeyCode::Tab), Button(GamepadButtonType::Select)],
+            ),
+            (EditorPaint, vec![Mouse(MouseButton::Left)]),
+            (
+                ZoomIn,
+                vec![
+                    Key(KeyCode::Equals),
+                    Key(KeyCode::NumpadAdd),
+                    Button(GamepadButtonType::RightTrigger),
+                ],
+            ),
+            (
+                ZoomOut,
+                vec![
+                    Key(KeyCode::Minus),
+                    Key(KeyCode::NumpadSubtract),
+                    Button(GamepadButtonType::LeftTrigger),
+                ],
+            ),
+            (Pan, vec![Mouse(MouseButton::Middle)]),
+            (BrushSingle, vec![Key(KeyCode::Key1)]),
+            (BrushRectangle, vec![Key(KeyCode::Key2)]),
+            (BrushLine, vec![Key(KeyCode::Key3)]),
+            (BrushFill, vec![Key(KeyCode::Key4)]),
+            (ToggleEraser, vec![chord(KeyCode::Delete, false, false)]),
+            (ClearLayer, vec![chord(KeyCode::Delete, false, true)]),
+            (Undo, vec![chord(KeyCode::Z, true, false)]),
+            (
+                Redo,
+                vec![
+                    chord(KeyCode::Z, true, true),
+                    chord(KeyCode::Y, true, false),
+                ],
+            ),
+            (SaveMap, vec![chord(KeyCode::S, true, false)]),
+            (LoadMap, vec![chord(KeyCode::L, true, false)]),
+        ];
+
+        InputMap {
+            bindings: bindings.into_iter().collect(),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct InputMapLoader;
+
+impl AssetLoader for InputMapLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let map: InputMap = ron::de::from_bytes(bytes)?;
+            load_context.set_default_asset(LoadedAsset::new(map));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["input.ron"]
+    }
+}
+
+/// Keeps the bindings file loaded so edits to it are picked up
+#[derive(Default)]
+pub struct InputMapFile {
+    pub handle: Option<Handle<InputMap>>,
+}
+
+/// Actions are updated right after bevy reads its input
+#[derive(Debug, Clone, PartialEq, Eq, Hash, SystemLabel)]
+pub struct ActionSystem;
+
+pub struct Plugin;
+
+impl BevyPlugin for Plugin {
+    fn build(&self, app: &mut App) {
+        app.add_asset::<InputMap>()
+            .init_asset_loader::<InputMapLoader>()
+            .init_resource::<InputMap>()
+            .init_resource::<InputMapFile>()
+            .init_resource::<Input<Action>>()
+            .add_startup_system(load_input_map)
+            .add_system(apply_input_map)
+            .add_system_to_stage(
+                CoreStage::PreUpdate,
+                update_actions.label(ActionSystem).after(InputSystem),
+            );
+    }
+}
+
+fn load_input_map(mut file: ResMut<InputMapFile>, asset_server: Res<AssetServer>) {
+    file.handle = Some(asset_server.load(INPUT_MAP_PATH));
+}
+
+/// Replaces the bindings whenever the bindings file loads or changes
+fn apply_input_map(
+    mut events: EventReader<AssetEvent<InputMap>>,
+    file: Res<InputMapFile>,
+    maps: Res<Assets<InputMap>>,
+    mut input_map: ResMut<InputMap>,
+) {
+    for event in events.iter() {
+        let handle = match event {
+            AssetEvent::Created { handle } | AssetEvent::Modified { handle } => handle,
+            AssetEvent::Removed { .. } => continue,
+        };
+        if file.handle.as_ref() != Some(handle) {
+            continue;
+        }
+        if let Some(map) = maps.get(handle) {
+            *input_map = map.clone();
+        }
+    }
+}
+
+/// Presses and releases actions from their bindings. Every action is just
+/// pressed or released for one frame, like `Input::clear`, but only actions
+/// pressed here are released here, so actions pressed directly stay held.
+#[allow(clippy::too_many_arguments)]
+fn update_actions(
+    input_map: Res<InputMap>,
+    keys: Res<Input<KeyCode>>,
+    mouse: Res<Input<MouseButton>>,
+    gamepads: Res<Gamepads>,
+    gamepad_buttons: Res<Input<GamepadButton>>,
+    gamepad_axes: Res<Axis<GamepadAxis>>,
+    mut held: Local<HashSet<Action>>,
+    mut actions: ResMut<Input<Action>>,
+) {
+    actions.clear();
+
+    let modifiers = Modifiers::from_input(&keys);
+    let is_active = |binding: &Binding| match *binding {
+        Binding::Key(key) => keys.pressed(key),
+        Binding::Chord {
+            key,
+            ctrl,
+            shift,
+            alt,
+        } => keys.pressed(key) && modifiers == Modifiers { shift, ctrl, alt },
+        Binding::Mouse(button) => mouse.pressed(button),
+        Binding::Button(button) => gamepads
+            .iter()
+            .any(|gamepad| gamepad_buttons.pressed(GamepadButton(*gamepad, button))),
+        Binding::Axis { axis, positive } => gamepads.iter().any(|gamepad| {
+            let value = gamepad_axes.get(GamepadAxis(*gamepad, axis)).unwrap_or(0.);
+            if positive {
+                value > AXIS_THRESHOLD
+            } else {
+                value < -AXIS_THRESHOLD
+            }
+        }),
+    };
+
+    // Actions that lost all their bindings are let go
+    let unbound: Vec<Action> = held
+        .iter()
+        .filter(|action| !input_map.bindings.contains_key(*action))
+        .copied()
+        .collect();
+    for action in unbound {
+        held.remove(&action);
+        actions.release(action);
+    }
+
+    for (action, bindings) in input_map.bindings.iter() {
+        if bindings.iter().any(is_active) {
+            if held.insert(*action) && !actions.pressed(*action) {
+                actions.press(*action);
+            }
+        } else if held.remove(action) {
+            actions.release(*action);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn app() -> App {
+        let mut app = App::new();
+        app.init_resource::<InputMap>()
+            .init_resource::<Input<KeyCode>>()
+            .init_resource::<Input<MouseButton>>()
+            .init_resource::<Gamepads>()
+            .init_resource::<Input<GamepadButton>>()
+            .init_resource::<Axis<GamepadAxis>>()
+            .init_resource::<Input<Action>>()
+            .add_system(update_actions.label(ActionSystem));
+        app
+    }
+
+    fn pressed(app: &App, action: Action) -> bool {
+        app.world
+            .get_resource::<Input<Action>>()
+            .unwrap()
+            .pressed(action)
+    }
+
+    #[test]
+    fn bound_keys_press_and_release_actions() {
+        let mut app = app();
+        app.world
+            .get_resource_mut::<Input<KeyCode>>()
+            .unwrap()
+            .press(KeyCode::Space);
+        app.update();
+        assert!(pressed(&app, Action::Jump));
+
+        app.world
+            .get_resource_mut::<Input<KeyCode>>()
+            .unwrap()
+            .release(KeyCode::Space);
+        app.update();
+        assert!(!pressed(&app, Action::Jump));
+    }
+
+    #[test]
+    fn rebinding_moves_an_action_to_another_key() {
+        let mut app = app();
+        app.world
+            .get_resource_mut::<InputMap>()
+            .unwrap()
+            .rebind(Action::Jump, Binding::Key(KeyCode::J));
+        app.world
+            .get_resource_mut::<Input<KeyCode>>()
+            .unwrap()
+            .press(KeyCode::Space);
+        app.update();
+        assert!(!pressed(&app, Action::Jump));
+
+        app.world
+            .get_resource_mut::<Input<KeyCode>>()
+            .unwrap()
+            .press(KeyCode::J);
+        app.update();
+        assert!(pressed(&app, Action::Jump));
+    }
+
+    fn just_pressed(app: &App, action: Action) -> bool {
+        app.world
+            .get_resource::<Input<Action>>()
+            .unwrap()
+            .just_pressed(action)
+    }
+
+    fn press_key(app: &mut App, key: KeyCode) {
+        app.world
+            .get_resource_mut::<Input<KeyCode>>()
+            .unwrap()
+            .press(key);
+    }
+
+    #[test]
+    fn the_bindings_file_matches_the_defaults() {
+        let bytes = std::fs::read(std::path::Path::new("assets").join(INPUT_MAP_PATH))
+            .expect("bindings file");
+        let file: InputMap = ron::de::from_bytes(&bytes).expect("bindings");
+        assert_eq!(file.bindings, InputMap::default().bindings);
+    }
+
+    fn press_interact_once(mut pressed: Local<bool>, mut actions: ResMut<Input<Action>>) {
+        if !*pressed {
+            actions.press(Action::Interact);
+            *pressed = true;
+        }
+    }
+
+    #[test]
+    fn synthetic_presses_are_just_pressed_for_one_frame() {
+        let mut app = app();
+        app.add_system(press_interact_once.after(ActionSystem));
+        app.update();
+        assert!(pressed(&app, Action::Interact));
+        assert!(just_pressed(&app, Action::Interact));
+
+        app.update();
+        assert!(pressed(&app, Action::Interact));
+        assert!(!just_pressed(&app, Action::Interact));
+    }
+
+    #[test]
+    fn bound_actions_are_just_pressed_for_one_frame() {
+        let mut app = app();
+        press_key(&mut app, KeyCode::Space);
+        app.update();
+        assert!(just_pressed(&app, Action::Jump));
+        app.update();
+        assert!(pressed(&app, Action::Jump));
+        assert!(!just_pressed(&app, Action::Jump));
+    }
+
+    #[test]
+    fn chords_need_exactly_their_modifiers() {
+        let mut app = app();
+        press_key(&mut app, KeyCode::Z);
+        app.update();
+        assert!(!pressed(&app, Action::Undo));
+
+        press_key(&mut app, KeyCode::LControl);
+        app.update();
+        assert!(pressed(&app, Action::Undo));
+        assert!(!pressed(&app, Action::Redo));
+
+        press_key(&mut app, KeyCode::RShift);
+        app.update();
+        assert!(!pressed(&app, Action::Undo));
+        assert!(pressed(&app, Action::Redo));
+    }
+}
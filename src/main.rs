@@ -1,20 +1,40 @@
 #![feature(int_abs_diff)]
+#![cfg_attr(test, feature(test))]
 
 use bevy::prelude::*;
 use bevy::{window::WindowDescriptor, DefaultPlugins};
 use bevy_inspector_egui::WorldInspectorPlugin;
 mod camera;
-mod mouse;
+mod characters;
+mod cursor;
+mod depth;
+mod editor_ui;
+mod history;
+mod input;
+mod map_file;
+mod movement;
+mod net;
 mod pathfinding;
+mod picking;
 mod player;
 mod sprite;
 mod tile_editor;
 mod tiles;
+mod tmx;
+mod traversal;
 mod utils;
 
 #[cfg(target_arch = "wasm32")]
 mod canvas_resizer;
 fn main() {
+    let net_mode = net::NetMode::from_args(std::env::args().skip(1));
+
+    #[cfg(not(target_arch = "wasm32"))]
+    if let net::NetMode::Server { addr, map } = &net_mode {
+        net::server::server_app(*addr, map.as_deref()).run();
+        return;
+    }
+
     let mut app = App::new();
 
     app.insert_resource(WindowDescriptor {
@@ -28,14 +48,36 @@ fn main() {
         Color::hex("291e31").expect("Color::hex(\"291e31\")"),
     ))
     .add_plugins(DefaultPlugins)
+    .add_plugin(input::Plugin)
     .add_plugin(camera::Plugin)
     .add_plugin(WorldInspectorPlugin::new())
     .add_plugin(tiles::Plugin)
+    .add_plugin(traversal::Plugin)
+    .add_plugin(tmx::Plugin)
+    .add_plugin(map_file::Plugin)
     .add_plugin(tile_editor::Plugin)
-    .add_plugin(mouse::Plugin)
+    .add_plugin(history::Plugin)
+    .add_plugin(editor_ui::Plugin)
+    .add_plugin(picking::Plugin)
+    .add_plugin(cursor::Plugin)
     .add_plugin(pathfinding::Plugin)
+    .add_plugin(characters::Plugin)
+    .add_plugin(sprite::Plugin)
+    .add_plugin(movement::Plugin)
+    .add_plugin(depth::Plugin)
     .add_plugin(player::Plugin);
 
+    app.insert_resource(map_file::MapName::from_args(std::env::args().skip(1)));
+    if let Some(startup) = tmx::TmxStartup::from_args(std::env::args().skip(1)) {
+        app.insert_resource(startup);
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    if let net::NetMode::Client { server } = &net_mode {
+        app.add_plugin(net::client::Plugin { server: *server });
+    }
+    app.insert_resource(net_mode);
+
     #[cfg(target_arch = "wasm32")]
     app.add_plugin(canvas_resizer::WebCanvasResizerPlugin);
     app.run();
@@ -0,0 +1,655 @@
+use std::fmt;
+
+use bevy::asset::{AssetLoader, BoxedFuture, LoadContext, LoadedAsset};
+use bevy::ecs::system::SystemParam;
+use bevy::math::Vec3Swizzles;
+use bevy::prelude::Plugin as BevyPlugin;
+use bevy::prelude::*;
+use bevy::reflect::TypeUuid;
+use bevy::utils::HashMap;
+use bevy_ecs_tilemap::{MapQuery, Tile, TileParent, TilePos};
+use bevy_tileset_map::prelude::Tilesets;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::depth::DepthSort;
+use crate::history::EditHistory;
+use crate::input::Action;
+use crate::movement::TileMover;
+use crate::pathfinding::{Destination, PathTask, TilePath};
+use crate::player::PlayerCharacter;
+use crate::tiles::{group_texture_index, spawn_map, tile_groups, MapBounds, MapLayer, MAP_ID};
+use crate::utils::*;
+
+/// Upgrades a map file's JSON from one version to the next
+type Migration = fn(Value) -> Result<Value, MapFileError>;
+
+/// `MIGRATIONS[i]` upgrades version `i + 1` to `i + 2`. The format hasn't
+/// changed since version 1; when it does, add a migration here.
+const MIGRATIONS: &[Migration] = &[];
+
+/// The version written by `save_map`. Older files are migrated on load.
+pub const MAP_FILE_VERSION: u32 = MIGRATIONS.len() as u32 + 1;
+
+/// Where maps are saved, relative to the asset folder
+const MAPS_DIR: &str = "maps";
+
+/// The map name used when none is given
+const DEFAULT_MAP_NAME: &str = "untitled";
+
+pub struct Plugin;
+
+impl BevyPlugin for Plugin {
+    fn build(&self, app: &mut App) {
+        app.add_asset::<MapFile>()
+            .init_asset_loader::<MapFileLoader>()
+            .add_event::<SaveMap>()
+            .add_event::<LoadMap>()
+            .init_resource::<PendingMapLoad>()
+            .init_resource::<MapName>()
+            .add_system(map_file_input)
+            .add_system(save_map)
+            .add_system(load_map)
+            .add_system(apply_loaded_map);
+    }
+}
+
+/// A named point characters can be placed at when a map is loaded
+#[derive(Debug, Clone, Component)]
+pub struct SpawnPoint {
+    pub name: String,
+}
+
+/// An entity that is saved with the map, identified by `kind`
+#[derive(Debug, Clone, Component)]
+pub struct Placement {
+    pub kind: String,
+}
+
+/// Saves the current map under this name
+pub struct SaveMap(pub String);
+
+/// Replaces the current map with the one saved under this name
+pub struct LoadMap(pub String);
+
+/// The map `Action::SaveMap`, `Action::LoadMap` and the editor panel save
+/// and load, from `--map name`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MapName(pub String);
+
+impl Default for MapName {
+    fn default() -> Self {
+        MapName(DEFAULT_MAP_NAME.to_string())
+    }
+}
+
+impl MapName {
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Self {
+        let args: Vec<String> = args.into_iter().collect();
+        args.iter()
+            .position(|a| a == "--map")
+            .and_then(|i| args.get(i + 1))
+            .filter(|name| !name.starts_with("--"))
+            .map_or_else(MapName::default, |name| MapName(name.clone()))
+    }
+}
+
+/// Saves and loads the map named by `MapName`
+#[derive(SystemParam)]
+pub struct MapFiles<'w, 's> {
+    pub name: ResMut<'w, MapName>,
+    save: EventWriter<'w, 's, SaveMap>,
+    load: EventWriter<'w, 's, LoadMap>,
+}
+
+impl<'w, 's> MapFiles<'w, 's> {
+    pub fn save(&mut self) {
+        self.save.send(SaveMap(self.name.0.clone()));
+    }
+
+    pub fn load(&mut self) {
+        self.load.send(LoadMap(self.name.0.clone()));
+    }
+}
+
+/// A map on disk. Tiles are stored by tileset and group name so files
+/// survive changes to texture indices.
+#[derive(Debug, Clone, Serialize, Deserialize, TypeUuid)]
+#[uuid = "a1d4e2b7-6c3f-4e8a-b5d9-7f2c1e0a9b64"]
+pub struct MapFile {
+    pub version: u32,
+    /// Size of the map in tiles
+    pub size: [u32; 2],
+    pub layers: Vec<LayerData>,
+    #[serde(default)]
+    pub spawn_points: Vec<SpawnPointData>,
+    #[serde(default)]
+    pub entities: Vec<PlacementData>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LayerData {
+    /// The `MapLayer` id
+    pub layer: u16,
+    pub tiles: Vec<TileData>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TileData {
+    pub pos: [u32; 2],
+    pub tileset: String,
+    pub group: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SpawnPointData {
+    pub name: String,
+    pub pos: [u32; 2],
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlacementData {
+    pub kind: String,
+    pub pos: [u32; 2],
+}
+
+#[derive(Debug)]
+pub enum MapFileError {
+    Json(serde_json::Error),
+    MissingVersion,
+    UnsupportedVersion(u64),
+}
+
+impl fmt::Display for MapFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MapFileError::Json(e) => write!(f, "invalid map file: {}", e),
+            MapFileError::MissingVersion => write!(f, "map file has no `version`"),
+            MapFileError::UnsupportedVersion(v) => write!(
+                f,
+                "map file version {} isn't supported, the newest is {}",
+                v, MAP_FILE_VERSION
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MapFileError {}
+
+impl From<serde_json::Error> for MapFileError {
+    fn from(e: serde_json::Error) -> Self {
+        MapFileError::Json(e)
+    }
+}
+
+impl MapFile {
+    /// Parses a map file of any known version, migrating it to the current one
+    pub fn from_slice(bytes: &[u8]) -> Result<Self, MapFileError> {
+        MapFile::migrate(bytes, MIGRATIONS)
+    }
+
+    /// Parses a map file, running `migrations` from its version on. The
+    /// newest version is the one after the last migration.
+    fn migrate(bytes: &[u8], migrations: &[Migration]) -> Result<Self, MapFileError> {
+        let newest = migrations.len() as u64 + 1;
+        let mut value: Value = serde_json::from_slice(bytes)?;
+        loop {
+            let version = value
+                .get("version")
+                .and_then(Value::as_u64)
+                .ok_or(MapFileError::MissingVersion)?;
+
+            value = match version {
+                v if v == newest => return Ok(serde_json::from_value(value)?),
+                v if (1..newest).contains(&v) => migrations[v as usize - 1](value)?,
+                v => return Err(MapFileError::UnsupportedVersion(v)),
+            };
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct MapFileLoader;
+
+impl AssetLoader for MapFileLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let map = MapFile::from_slice(bytes)?;
+            load_context.set_default_asset(LoadedAsset::new(map));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["map.json"]
+    }
+}
+
+pub fn map_path(name: &str) -> String {
+    format!("{}/{}.map.json", MAPS_DIR, name)
+}
+
+/// The map file being loaded by a `LoadMap` request
+#[derive(Default)]
+pub struct PendingMapLoad {
+    handle: Option<Handle<MapFile>>,
+    /// The file has finished loading and waits for the tileset
+    loaded: bool,
+}
+
+impl PendingMapLoad {
+    fn start(&mut self, handle: Handle<MapFile>) {
+        self.handle = Some(handle);
+        self.loaded = false;
+    }
+}
+
+/// `Action::SaveMap` saves and `Action::LoadMap` loads the `MapName` map
+fn map_file_input(actions: Res<Input<Action>>, mut files: MapFiles) {
+    if actions.just_pressed(Action::SaveMap) {
+        files.save();
+    }
+    if actions.just_pressed(Action::LoadMap) {
+        files.load();
+    }
+}
+
+fn save_map(
+    mut events: EventReader<SaveMap>,
+    tiles: Query<(&TilePos, &Tile, &TileParent)>,
+    spawn_points: Query<(&SpawnPoint, &Transform)>,
+    placements: Query<(&Placement, &Transform)>,
+    bounds: Res<MapBounds>,
+    mut map_query: MapQuery,
+    tilesets: Tilesets,
+) {
+    for SaveMap(name) in events.iter() {
+        let tileset = match (
+            tilesets.get_by_name("terrain"),
+            map_query.get_layer(MAP_ID, MapLayer::Ground.id()),
+        ) {
+            (Some(tileset), Some(_)) => tileset,
+            _ => {
+                warn!("Can't save map {:?} before it is built", name);
+                continue;
+            }
+        };
+
+        let groups: HashMap<u16, &str> = tile_groups(tileset)
+            .filter_map(|(_, group)| group_texture_index(tileset, group).map(|i| (i, group)))
+            .collect();
+
+        // The overlay only holds transient highlights
+        let mut layers: Vec<LayerData> = MapLayer::ALL
+            .iter()
+            .filter(|l| **l != MapLayer::Overlay)
+            .map(|l| LayerData {
+                layer: l.id(),
+                tiles: Vec::new(),
+            })
+            .collect();
+
+        for (tp, tile, parent) in tiles.iter() {
+            if parent.map_id != MAP_ID {
+                continue;
+            }
+            let layer = match layers.iter_mut().find(|l| l.layer == parent.layer_id) {
+                Some(layer) => layer,
+                None => continue,
+            };
+            match groups.get(&tile.texture_index) {
+                Some(group) => layer.tiles.push(TileData {
+                    pos: [tp.0, tp.1],
+                    tileset: tileset.name().to_string(),
+                    group: group.to_string(),
+                }),
+                None => warn!("No tile group for texture index {}", tile.texture_index),
+            }
+        }
+
+        let to_pos = |t: &Transform| {
+            let p = project_iso(&t.translation.xy()).round();
+            [p.x.max(0.) as u32, p.y.max(0.) as u32]
+        };
+
+        let file = MapFile {
+            version: MAP_FILE_VERSION,
+            size: [bounds.width, bounds.height],
+            layers,
+            spawn_points: spawn_points
+                .iter()
+                .map(|(s, t)| SpawnPointData {
+                    name: s.name.clone(),
+                    pos: to_pos(t),
+                })
+                .collect(),
+            entities: placements
+                .iter()
+                .map(|(p, t)| PlacementData {
+                    kind: p.kind.clone(),
+                    pos: to_pos(t),
+                })
+                .collect(),
+        };
+
+        match serde_json::to_string_pretty(&file) {
+            Ok(json) => write_map(name, &json),
+            Err(e) => error!("Failed to serialize map {:?}: {}", name, e),
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn write_map(name: &str, json: &str) {
+    let path = std::path::Path::new("assets").join(map_path(name));
+    let result = path
+        .parent()
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|_| std::fs::write(&path, json));
+
+    match result {
+        Ok(_) => info!("Saved map to {:?}", path),
+        Err(e) => error!("Failed to save map to {:?}: {}", path, e),
+    }
+}
+
+/// The browser can't write to the asset folder, so maps are kept in local storage
+#[cfg(target_arch = "wasm32")]
+fn write_map(name: &str, json: &str) {
+    let storage = web_sys::window().and_then(|w| w.local_storage().ok().flatten());
+    match storage.map(|s| s.set_item(&map_path(name), json)) {
+        Some(Ok(_)) => info!("Saved map {:?} to local storage", name),
+        _ => error!("Failed to save map {:?} to local storage", name),
+    }
+}
+
+/// Reads a saved map straight from disk, bypassing the asset cache
+#[cfg(not(target_arch = "wasm32"))]
+fn read_saved_map(name: &str) -> anyhow::Result<MapFile> {
+    let bytes = std::fs::read(std::path::Path::new("assets").join(map_path(name)))?;
+    Ok(MapFile::from_slice(&bytes)?)
+}
+
+#[cfg(target_arch = "wasm32")]
+fn read_stored_map(name: &str) -> Option<String> {
+    web_sys::window()?
+        .local_storage()
+        .ok()??
+        .get_item(&map_path(name))
+        .ok()?
+}
+
+fn load_map(
+    mut events: EventReader<LoadMap>,
+    mut pending: ResMut<PendingMapLoad>,
+    asset_server: Res<AssetServer>,
+    mut map_files: ResMut<Assets<MapFile>>,
+) {
+    for LoadMap(name) in events.iter() {
+        #[cfg(target_arch = "wasm32")]
+        if let Some(json) = read_stored_map(name) {
+            match MapFile::from_slice(json.as_bytes()) {
+                Ok(file) => pending.start(map_files.add(file)),
+                Err(e) => error!("Failed to load map {:?}: {}", name, e),
+            }
+            continue;
+        }
+
+        let handle: Handle<MapFile> = asset_server.load(map_path(name).as_str());
+        // A map that was loaded before is cached, so read it again in case
+        // it was saved since
+        #[cfg(not(target_arch = "wasm32"))]
+        if map_files.contains(&handle) {
+            match read_saved_map(name) {
+                Ok(file) => map_files.set_untracked(&handle, file),
+                Err(e) => {
+                    error!("Failed to load map {:?}: {}", name, e);
+                    continue;
+                }
+            }
+        }
+        pending.start(handle);
+    }
+}
+
+/// Replaces the map once a `LoadMap` file and the tileset are ready. Edits of
+/// the old map can't be undone afterwards, and the player moves to the first
+/// spawn point.
+#[allow(clippy::too_many_arguments)]
+fn apply_loaded_map(
+    mut asset_events: EventReader<AssetEvent<MapFile>>,
+    mut pending: ResMut<PendingMapLoad>,
+    map_files: Res<Assets<MapFile>>,
+    existing: Query<Entity, Or<(With<SpawnPoint>, With<Placement>)>>,
+    mut players: Query<(Entity, &mut TileMover), With<PlayerCharacter>>,
+    mut history: ResMut<EditHistory>,
+    mut map_query: MapQuery,
+    tilesets: Tilesets,
+    mut commands: Commands,
+) {
+    let pending_handle = match pending.handle.clone() {
+        Some(handle) => handle,
+        None => return,
+    };
+    for event in asset_events.iter() {
+        if let AssetEvent::Created { handle } | AssetEvent::Modified { handle } = event {
+            if *handle == pending_handle {
+                pending.loaded = true;
+            }
+        }
+    }
+    if !pending.loaded {
+        return;
+    }
+
+    // Keep the request until the tileset is there to build the map with
+    let (file, tileset) = match (
+        map_files.get(&pending_handle),
+        tilesets.get_by_name("terrain"),
+    ) {
+        (Some(file), Some(tileset)) => (file, tileset),
+        _ => return,
+    };
+    *pending = PendingMapLoad::default();
+
+    let mut tiles: HashMap<(MapLayer, TilePos), Tile> = HashMap::default();
+    for layer_data in file.layers.iter() {
+        let layer = match MapLayer::from_id(layer_data.layer) {
+            Some(layer) => layer,
+            None => {
+                warn!("Skipping unknown map layer {}", layer_data.layer);
+                continue;
+            }
+        };
+
+        for t in layer_data.tiles.iter() {
+            let index = if t.tileset == tileset.name() {
+                group_texture_index(tileset, &t.group)
+            } else {
+                None
+            };
+            match index {
+                Some(texture_index) => {
+                    tiles.insert(
+                        (layer, TilePos(t.pos[0], t.pos[1])),
+                        Tile {
+                            texture_index,
+                            ..Default::default()
+                        },
+                    );
+                }
+                None => warn!("Unknown tile {}/{}", t.tileset, t.group),
+            }
+        }
+    }
+
+    map_query.despawn(&mut commands, MAP_ID);
+    for e in existing.iter() {
+        commands.entity(e).despawn_recursive();
+    }
+
+    spawn_map(
+        &mut commands,
+        &mut map_query,
+        tileset,
+        (file.size[0], file.size[1]),
+        |layer, pos| tiles.remove(&(layer, pos)),
+    );
+    history.clear();
+
+    if let Some(spawn) = file.spawn_points.first() {
+        for (e, mut mover) in players.iter_mut() {
+            mover.teleport(TilePos(spawn.pos[0], spawn.pos[1]));
+            commands
+                .entity(e)
+                .remove::<Destination>()
+                .remove::<PathTask>()
+                .remove::<TilePath>();
+        }
+    }
+
+    let to_transform = |pos: [u32; 2]| {
+        Transform::from_translation(
+            iso_to_world(&Vec2::new(pos[0] as f32, pos[1] as f32)).extend(0.),
+        )
+    };
+
+    for s in file.spawn_points.iter() {
+        commands
+            .spawn()
+            .insert(SpawnPoint {
+                name: s.name.clone(),
+            })
+            .insert(to_transform(s.pos))
+            .insert(GlobalTransform::default());
+    }
+
+    for p in file.entities.iter() {
+        commands
+            .spawn()
+            .insert(Placement {
+                kind: p.kind.clone(),
+            })
+            .insert(to_transform(p.pos))
+            .insert(DepthSort::default())
+            .insert(GlobalTransform::default());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Test-only fixture: a made-up older layout with one ground layer of
+    /// "terrain" tiles, to check that migrations run in order
+    #[derive(Deserialize)]
+    struct FixtureV1 {
+        width: u32,
+        height: u32,
+        tiles: Vec<FixtureTile>,
+    }
+
+    #[derive(Deserialize)]
+    struct FixtureTile {
+        pos: [u32; 2],
+        group: String,
+    }
+
+    fn migrate_fixture(value: Value) -> Result<Value, MapFileError> {
+        let v1: FixtureV1 = serde_json::from_value(value)?;
+        let ground = LayerData {
+            layer: MapLayer::Ground.id(),
+            tiles: v1
+                .tiles
+                .into_iter()
+                .map(|t| TileData {
+                    pos: t.pos,
+                    tileset: "terrain".to_string(),
+                    group: t.group,
+                })
+                .collect(),
+        };
+        Ok(serde_json::to_value(MapFile {
+            version: 2,
+            size: [v1.width, v1.height],
+            layers: vec![ground],
+            spawn_points: Vec::new(),
+            entities: Vec::new(),
+        })?)
+    }
+
+    #[test]
+    fn older_versions_are_migrated() {
+        let v1 = r#"{
+            "version": 1,
+            "width": 3,
+            "height": 2,
+            "tiles": [
+                { "pos": [0, 0], "group": "grass" },
+                { "pos": [2, 1], "group": "water" }
+            ]
+        }"#;
+        let file =
+            MapFile::migrate(v1.as_bytes(), &[migrate_fixture as Migration]).expect("migrate v1");
+
+        assert_eq!(file.version, 2);
+        assert_eq!(file.size, [3, 2]);
+        assert!(file.spawn_points.is_empty());
+        assert!(file.entities.is_empty());
+
+        assert_eq!(file.layers.len(), 1);
+        let ground = &file.layers[0];
+        assert_eq!(ground.layer, MapLayer::Ground.id());
+        let tiles: Vec<_> = ground
+            .tiles
+            .iter()
+            .map(|t| (t.pos, t.tileset.as_str(), t.group.as_str()))
+            .collect();
+        assert_eq!(
+            tiles,
+            [([0, 0], "terrain", "grass"), ([2, 1], "terrain", "water")]
+        );
+    }
+
+    #[test]
+    fn saved_files_load_as_they_are() {
+        let file = MapFile {
+            version: MAP_FILE_VERSION,
+            size: [2, 2],
+            layers: Vec::new(),
+            spawn_points: vec![SpawnPointData {
+                name: "start".to_string(),
+                pos: [1, 0],
+            }],
+            entities: Vec::new(),
+        };
+        let json = serde_json::to_vec(&file).unwrap();
+        let loaded = MapFile::from_slice(&json).expect("load saved map");
+        assert_eq!(loaded.size, [2, 2]);
+        assert_eq!(loaded.spawn_points[0].pos, [1, 0]);
+    }
+
+    #[test]
+    fn the_map_name_comes_from_the_command_line() {
+        let args = |args: &[&str]| MapName::from_args(args.iter().map(|a| a.to_string()));
+        assert_eq!(args(&["--map", "castle"]), MapName("castle".to_string()));
+        assert_eq!(args(&["--map", "--tmx"]), MapName::default());
+        assert_eq!(args(&[]), MapName(DEFAULT_MAP_NAME.to_string()));
+    }
+
+    #[test]
+    fn rejects_missing_and_future_versions() {
+        assert!(matches!(
+            MapFile::from_slice(br#"{ "size": [1, 1], "layers": [] }"#),
+            Err(MapFileError::MissingVersion)
+        ));
+        assert!(matches!(
+            MapFile::from_slice(br#"{ "version": 99 }"#),
+            Err(MapFileError::UnsupportedVersion(99))
+        ));
+    }
+}
@@ -0,0 +1,245 @@
+use bevy::core::FixedTimestep;
+use bevy::prelude::Plugin as BevyPlugin;
+use bevy::prelude::*;
+use bevy::transform::TransformSystem;
+use bevy_ecs_tilemap::TilePos;
+
+use crate::pathfinding::{NavGrid, TilePath};
+use crate::picking::tile_to_world;
+use crate::sprite::Facing;
+use crate::traversal::BASE_STEP_COST;
+
+/// Label of the fixed timestep movement is simulated at
+pub const MOVEMENT_TIMESTEP: &str = "movement";
+const MOVEMENT_TICKS_PER_SECOND: f64 = 30.;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, SystemLabel)]
+pub enum MovementSystem {
+    Interpolate,
+}
+
+/// How fast an entity walks along its `TilePath`, in tiles per second on
+/// terrain with a cost multiplier of `1.0`
+#[derive(Debug, Component, Clone, Copy)]
+pub struct MovementSpeed(pub f32);
+
+impl Default for MovementSpeed {
+    fn default() -> Self {
+        MovementSpeed(4.)
+    }
+}
+
+/// A step between two neighbouring tiles
+#[derive(Debug, Clone, Copy)]
+struct Step {
+    from: TilePos,
+    to: TilePos,
+    /// Fraction of the step done, from 0 to 1
+    progress: f32,
+    /// Seconds the whole step takes
+    duration: f32,
+}
+
+/// Moves an entity tile by tile along its `TilePath`. Movement is simulated
+/// on a fixed timestep and the `Transform` is interpolated between ticks.
+#[derive(Debug, Component, Clone)]
+pub struct TileMover {
+    tile: TilePos,
+    step: Option<Step>,
+    previous: Vec2,
+    current: Vec2,
+}
+
+impl TileMover {
+    pub fn new(tile: TilePos) -> Self {
+        let p = tile_to_world(tile);
+        TileMover {
+            tile,
+            step: None,
+            previous: p,
+            current: p,
+        }
+    }
+
+    /// The tile the entity stands on, or last left while stepping
+    pub fn tile(&self) -> TilePos {
+        self.tile
+    }
+
+    /// The tile the entity will stand on once the current step finishes
+    pub fn next_tile(&self) -> TilePos {
+        self.step.map_or(self.tile, |step| step.to)
+    }
+
+    pub fn is_stepping(&self) -> bool {
+        self.step.is_some()
+    }
+
+    /// Puts the entity on `tile` straight away, dropping any step
+    pub fn teleport(&mut self, tile: TilePos) {
+        *self = TileMover::new(tile);
+    }
+}
+
+/// An entity finished a step onto a new tile
+#[derive(Debug, Clone, Copy)]
+pub struct TileStepped {
+    pub entity: Entity,
+    pub from: TilePos,
+    pub to: TilePos,
+}
+
+/// An entity reached the end of its `TilePath`
+#[derive(Debug, Clone, Copy)]
+pub struct PathArrived {
+    pub entity: Entity,
+    pub tile: TilePos,
+}
+
+pub struct Plugin;
+
+impl BevyPlugin for Plugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<TileStepped>()
+            .add_event::<PathArrived>()
+            .add_stage_after(
+                CoreStage::Update,
+                MOVEMENT_TIMESTEP,
+                SystemStage::parallel()
+                    .with_run_criteria(
+                        FixedTimestep::steps_per_second(MOVEMENT_TICKS_PER_SECOND)
+                            .with_label(MOVEMENT_TIMESTEP),
+                    )
+                    .with_system(advance_movers),
+            )
+            .add_system_to_stage(
+                CoreStage::PostUpdate,
+                interpolate_movers
+                    .label(MovementSystem::Interpolate)
+                    .before(TransformSystem::TransformPropagate),
+            );
+    }
+}
+
+/// Seconds a step from `from` onto `to` takes at `speed`, or `None` if `to`
+/// can't be entered
+fn step_duration(grid: &NavGrid, from: TilePos, to: TilePos, speed: f32) -> Option<f32> {
+    let cost = grid.step_cost(to)?;
+    let multiplier = cost as f32 / BASE_STEP_COST as f32;
+    let length = if from.0 != to.0 && from.1 != to.1 {
+        std::f32::consts::SQRT_2
+    } else {
+        1.
+    };
+    Some(length * multiplier / speed.max(f32::EPSILON))
+}
+
+fn advance_movers(
+    grid: Res<NavGrid>,
+    mut commands: Commands,
+    mut stepped: EventWriter<TileStepped>,
+    mut arrived: EventWriter<PathArrived>,
+    mut query: Query<(
+        Entity,
+        &MovementSpeed,
+        &mut TileMover,
+        Option<&mut TilePath>,
+    )>,
+) {
+    let dt = (1. / MOVEMENT_TICKS_PER_SECOND) as f32;
+
+    for (e, speed, mut mover, mut path) in query.iter_mut() {
+        mover.previous = mover.current;
+        let mut budget = dt;
+
+        while budget > 0. {
+            let mut step = match mover.step {
+                Some(step) => step,
+                None => {
+                    let path = match path.as_deref_mut() {
+                        Some(path) => path,
+                        None => break,
+                    };
+                    // Paths may start on the tile the entity is already on
+                    while path.0.last() == Some(&mover.tile) {
+                        path.0.pop();
+                    }
+                    let to = match path.0.pop() {
+                        Some(to) => to,
+                        None => {
+                            commands.entity(e).remove::<TilePath>();
+                            arrived.send(PathArrived {
+                                entity: e,
+                                tile: mover.tile,
+                            });
+                            break;
+                        }
+                    };
+                    let from = mover.tile;
+                    let duration = match step_duration(&grid, from, to, speed.0) {
+                        Some(duration) => duration,
+                        // The map changed under the path, so stop here
+                        None => {
+                            commands.entity(e).remove::<TilePath>();
+                            break;
+                        }
+                    };
+
+                    let dx = to.0 as i64 - from.0 as i64;
+                    let dy = to.1 as i64 - from.1 as i64;
+                    if let Some(facing) = Facing::from_step(dx, dy) {
+                        commands.entity(e).insert(facing);
+                    }
+
+                    Step {
+                        from,
+                        to,
+                        progress: 0.,
+                        duration,
+                    }
+                }
+            };
+
+            let remaining = (1. - step.progress) * step.duration;
+            if budget < remaining {
+                step.progress += budget / step.duration;
+                mover.step = Some(step);
+                break;
+            }
+
+            budget -= remaining;
+            mover.step = None;
+            mover.tile = step.to;
+            stepped.send(TileStepped {
+                entity: e,
+                from: step.from,
+                to: step.to,
+            });
+        }
+
+        mover.current = match mover.step {
+            Some(step) => tile_to_world(step.from).lerp(tile_to_world(step.to), step.progress),
+            None => tile_to_world(mover.tile),
+        };
+    }
+}
+
+/// Places movers between their last two simulated positions by how far the
+/// fixed timestep is into its next tick
+fn interpolate_movers(
+    timesteps: Res<FixedTimesteps>,
+    mut query: Query<(&TileMover, &mut Transform)>,
+) {
+    let alpha = timesteps
+        .get(MOVEMENT_TIMESTEP)
+        .map_or(1., |state| state.overstep_percentage() as f32)
+        .clamp(0., 1.);
+
+    for (mover, mut t) in query.iter_mut() {
+        let p = mover.previous.lerp(mover.current, alpha);
+        if t.translation.truncate() != p {
+            t.translation.x = p.x;
+            t.translation.y = p.y;
+        }
+    }
+}
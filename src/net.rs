@@ -0,0 +1,153 @@
+//! Client/server play over renet: https://github.com/lucaspoffo/renet
+
+use std::net::{Ipv4Addr, SocketAddr};
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::sprite::{Animation, Facing};
+
+#[cfg(not(target_arch = "wasm32"))]
+pub mod client;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod server;
+
+/// Clients and servers with a different protocol id refuse each other
+pub const PROTOCOL_ID: u64 = 0x7275_6e79_7800_0001;
+pub const DEFAULT_PORT: u16 = 5000;
+/// Signs connect tokens. Clients make their own tokens, so this only keeps
+/// out other games, not other players.
+#[cfg(not(target_arch = "wasm32"))]
+pub const PRIVATE_KEY: &[u8; renet::NETCODE_KEY_BYTES] = b"runyx local play key, not secret";
+
+/// Renet channel ids, in the order of `RenetConnectionConfig::default`
+pub const RELIABLE_CHANNEL: u8 = 0;
+pub const UNRELIABLE_CHANNEL: u8 = 1;
+
+/// Whether the game runs on its own, as a headless server or as a client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NetMode {
+    Offline,
+    /// Owns the map and all movement. `map` names a saved map to serve,
+    /// otherwise every tile is walkable.
+    Server {
+        addr: SocketAddr,
+        map: Option<String>,
+    },
+    /// Sends click-to-move requests and shows what the server replicates
+    Client {
+        server: SocketAddr,
+    },
+}
+
+impl Default for NetMode {
+    fn default() -> Self {
+        NetMode::Offline
+    }
+}
+
+impl NetMode {
+    /// Reads `--server [addr] [--map name]` or `--client [addr]` from the
+    /// command line. Addresses default to localhost.
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Self {
+        let localhost = SocketAddr::from((Ipv4Addr::LOCALHOST, DEFAULT_PORT));
+        let args: Vec<String> = args.into_iter().collect();
+        let addr_after = |i: usize| {
+            args.get(i + 1)
+                .and_then(|a| a.parse().ok())
+                .unwrap_or(localhost)
+        };
+        let map = args
+            .iter()
+            .position(|a| a == "--map")
+            .and_then(|i| args.get(i + 1).cloned());
+
+        if let Some(i) = args.iter().position(|a| a == "--server") {
+            NetMode::Server {
+                addr: addr_after(i),
+                map,
+            }
+        } else if let Some(i) = args.iter().position(|a| a == "--client") {
+            NetMode::Client {
+                server: addr_after(i),
+            }
+        } else {
+            NetMode::Offline
+        }
+    }
+
+    pub fn is_client(&self) -> bool {
+        matches!(self, NetMode::Client { .. })
+    }
+
+    /// Clients play on the server's map, so only offline games edit maps
+    pub fn can_edit(&self) -> bool {
+        !self.is_client()
+    }
+}
+
+/// A character controlled by the client with this id
+#[derive(Debug, Component, Clone, Copy, PartialEq, Eq)]
+pub struct NetPlayer {
+    pub id: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ClientMessage {
+    /// Walk the sender's character to this tile
+    MoveTo { goal: [u32; 2] },
+    /// Play a one-shot animation on the sender's character
+    Play { animation: Animation },
+}
+
+/// The map a server plays on. Maps are too big for one message, so clients
+/// load their own copy of it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MapInfo {
+    /// The saved map, or `None` for an open map where every tile is walkable
+    pub name: Option<String>,
+    pub size: [u32; 2],
+    /// `map_hash` of the map file, so clients can tell their copy differs
+    pub hash: u64,
+}
+
+/// FNV-1a hash of a map file, the same for every build and platform
+#[cfg(not(target_arch = "wasm32"))]
+pub fn map_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Messages sent on the reliable channel
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ServerMessage {
+    Welcome {
+        id: u64,
+        map: MapInfo,
+    },
+    PlayerLeft {
+        id: u64,
+    },
+    /// A one-shot animation for the character of client `id`. Snapshots
+    /// only carry the looping animation, which follows movement.
+    Play {
+        id: u64,
+        animation: Animation,
+    },
+}
+
+/// Where every character is, sent on the unreliable channel every tick
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub players: Vec<PlayerState>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlayerState {
+    pub id: u64,
+    pub character: String,
+    pub position: [f32; 2],
+    pub facing: Facing,
+    pub animation: Animation,
+}
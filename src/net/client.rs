@@ -0,0 +1,276 @@
+use std::net::{SocketAddr, UdpSocket};
+use std::time::SystemTime;
+
+use bevy::prelude::Plugin as BevyPlugin;
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use renet::{ConnectToken, RenetClient, RenetConnectionConfig};
+
+use super::{
+    map_hash, ClientMessage, MapInfo, NetPlayer, ServerMessage, Snapshot, PRIVATE_KEY, PROTOCOL_ID,
+    RELIABLE_CHANNEL, UNRELIABLE_CHANNEL,
+};
+use crate::camera::CameraTarget;
+use crate::characters::{CharacterSheet, CharacterSheets};
+use crate::depth::DepthSort;
+use crate::input::Action;
+use crate::map_file::{map_path, LoadMap};
+use crate::picking::CursorTile;
+use crate::sprite::{
+    spawn_character_sprite, Animation, AnimationController, AnimationPlayer, CharacterAppearance,
+};
+use crate::tile_editor::EditorState;
+
+/// How quickly replicated characters catch up with the latest snapshot, per
+/// second
+const SNAPSHOT_SMOOTHING: f32 = 15.;
+/// How long a client has to use its connect token
+const CONNECT_TOKEN_EXPIRE_SECONDS: u64 = 300;
+/// How long the connection lasts without hearing from the server
+const CONNECTION_TIMEOUT_SECONDS: i32 = 15;
+
+/// Connects to the server at `server` and shows the characters it replicates
+/// in place of the local player
+pub struct Plugin {
+    pub server: SocketAddr,
+}
+
+/// This client's id, and the map the server plays on once it has welcomed us
+#[derive(Debug, Clone)]
+pub struct NetSession {
+    pub id: u64,
+    pub map: Option<MapInfo>,
+}
+
+/// Where the server last placed a replicated character
+#[derive(Debug, Component, Clone, Copy)]
+struct NetPosition(Vec2);
+
+impl BevyPlugin for Plugin {
+    fn build(&self, app: &mut App) {
+        let (client, id) = new_renet_client(self.server);
+        app.insert_resource(client)
+            .insert_resource(NetSession { id, map: None })
+            .add_system_set(SystemSet::on_update(EditorState::Play).with_system(send_move_to))
+            .add_system(send_jump)
+            .add_system(receive_server_messages)
+            .add_system(apply_snapshots)
+            .add_system(smooth_net_positions.after(apply_snapshots))
+            .add_system_to_stage(CoreStage::PreUpdate, update_client)
+            .add_system_to_stage(CoreStage::PostUpdate, send_client_packets);
+    }
+}
+
+/// A client for the server at `server`, and its id
+pub(crate) fn new_renet_client(server: SocketAddr) -> (RenetClient, u64) {
+    let socket = UdpSocket::bind("0.0.0.0:0").expect("bind client socket");
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("system time");
+    // Ids only have to not collide between clients
+    let id = rand::random::<u64>();
+    let token = ConnectToken::generate(
+        now,
+        PROTOCOL_ID,
+        CONNECT_TOKEN_EXPIRE_SECONDS,
+        id,
+        CONNECTION_TIMEOUT_SECONDS,
+        vec![server],
+        None,
+        PRIVATE_KEY,
+    )
+    .expect("generate connect token");
+    let client = RenetClient::new(now, socket, id, token, RenetConnectionConfig::default())
+        .expect("start renet client");
+    (client, id)
+}
+
+/// Reads packets from the server
+fn update_client(time: Res<Time>, mut client: ResMut<RenetClient>) {
+    if let Err(e) = client.update(time.delta()) {
+        warn!("receiving from server: {}", e);
+    }
+}
+
+/// Sends the messages queued this frame
+fn send_client_packets(mut client: ResMut<RenetClient>) {
+    if let Err(e) = client.send_packets() {
+        warn!("sending to server: {}", e);
+    }
+}
+
+/// Asks the server to walk our character to the hovered tile on
+/// `Action::Interact`, like `tile_editor::on_tile_click` does offline
+fn send_move_to(
+    actions: Res<Input<Action>>,
+    cursor_tile: Res<CursorTile>,
+    mut client: ResMut<RenetClient>,
+) {
+    if !actions.just_pressed(Action::Interact) || actions.pressed(Action::Select) {
+        return;
+    }
+    if let Some(goal) = cursor_tile.current {
+        let message = ClientMessage::MoveTo {
+            goal: [goal.0, goal.1],
+        };
+        if let Ok(message) = bincode::serialize(&message) {
+            if let Err(e) = client.send_message(RELIABLE_CHANNEL, message) {
+                warn!("sending move to server: {}", e);
+            }
+        }
+    }
+}
+
+/// Asks the server to play the jump on our character, like
+/// `player::jump_input` does offline
+fn send_jump(actions: Res<Input<Action>>, mut client: ResMut<RenetClient>) {
+    if !actions.just_pressed(Action::Jump) {
+        return;
+    }
+    let message = ClientMessage::Play {
+        animation: Animation::Jump,
+    };
+    if let Ok(message) = bincode::serialize(&message) {
+        if let Err(e) = client.send_message(RELIABLE_CHANNEL, message) {
+            warn!("sending jump to server: {}", e);
+        }
+    }
+}
+
+fn receive_server_messages(
+    mut client: ResMut<RenetClient>,
+    mut session: ResMut<NetSession>,
+    mut load: EventWriter<LoadMap>,
+    mut commands: Commands,
+    mut players: Query<(Entity, &NetPlayer, &mut AnimationController)>,
+) {
+    while let Some(message) = client.receive_message(RELIABLE_CHANNEL) {
+        let message: ServerMessage = match bincode::deserialize(&message) {
+            Ok(message) => message,
+            Err(e) => {
+                warn!("bad message from server: {}", e);
+                continue;
+            }
+        };
+
+        match message {
+            ServerMessage::Welcome { id, map } => {
+                info!("joined as client {}", id);
+                session.id = id;
+                if let Some(name) = map.name.as_deref() {
+                    load_server_map(name, map.hash, &mut load);
+                }
+                session.map = Some(map);
+            }
+            ServerMessage::PlayerLeft { id } => {
+                for (e, player, _) in players.iter() {
+                    if player.id == id {
+                        commands.entity(e).despawn_recursive();
+                    }
+                }
+            }
+            ServerMessage::Play { id, animation } => {
+                for (_, player, mut controller) in players.iter_mut() {
+                    if player.id == id {
+                        controller.play_once(animation);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Loads our copy of the map the server plays on, unless it differs from
+/// the server's
+fn load_server_map(name: &str, hash: u64, load: &mut EventWriter<LoadMap>) {
+    let path = std::path::Path::new("assets").join(map_path(name));
+    match std::fs::read(&path) {
+        Ok(bytes) if map_hash(&bytes) == hash => load.send(LoadMap(name.to_string())),
+        Ok(_) => warn!("the server's map {} differs from {}", name, path.display()),
+        Err(e) => warn!("reading the server's map {}: {}", name, e),
+    }
+}
+
+/// Spawns, moves and animates replicated characters from the newest snapshot
+/// this frame
+fn apply_snapshots(
+    mut client: ResMut<RenetClient>,
+    session: Res<NetSession>,
+    sheets: Res<CharacterSheets>,
+    sheet_assets: Res<Assets<CharacterSheet>>,
+    mut commands: Commands,
+    mut players: Query<(
+        Entity,
+        &NetPlayer,
+        &mut NetPosition,
+        &mut AnimationController,
+    )>,
+) {
+    let mut snapshot = None;
+    while let Some(message) = client.receive_message(UNRELIABLE_CHANNEL) {
+        match bincode::deserialize::<Snapshot>(&message) {
+            Ok(s) => snapshot = Some(s),
+            Err(e) => warn!("bad snapshot from server: {}", e),
+        }
+    }
+    let snapshot = match snapshot {
+        Some(snapshot) => snapshot,
+        None => return,
+    };
+    let sheet = match sheet_assets.get(&sheets.handle) {
+        Some(sheet) => sheet,
+        None => return,
+    };
+
+    let mut existing: HashMap<u64, Entity> = HashMap::default();
+    for (e, player, mut position, mut controller) in players.iter_mut() {
+        existing.insert(player.id, e);
+        if let Some(state) = snapshot.players.iter().find(|s| s.id == player.id) {
+            position.0 = Vec2::from(state.position);
+            if controller.base != state.animation {
+                controller.base = state.animation;
+            }
+            commands.entity(e).insert(state.facing);
+        }
+    }
+
+    for state in snapshot.players.iter() {
+        if existing.contains_key(&state.id) {
+            continue;
+        }
+        let position = Vec2::from(state.position);
+        let e = commands
+            .spawn()
+            .insert(Transform::from_translation(position.extend(0.)))
+            .insert(GlobalTransform::default())
+            .insert(DepthSort::default())
+            .insert(CharacterAppearance::new(state.character.clone()))
+            .insert(AnimationPlayer::default())
+            .insert(AnimationController {
+                base: state.animation,
+                ..Default::default()
+            })
+            .insert(state.facing)
+            .insert(NetPosition(position))
+            .insert(NetPlayer { id: state.id })
+            .id();
+        if state.id == session.id {
+            commands.entity(e).insert(CameraTarget);
+        }
+        spawn_character_sprite(&mut commands, e, sheet);
+    }
+}
+
+/// Eases replicated characters toward their last snapshot position, which
+/// arrives less often than frames are drawn
+fn smooth_net_positions(time: Res<Time>, mut query: Query<(&NetPosition, &mut Transform)>) {
+    let t = (time.delta_seconds() * SNAPSHOT_SMOOTHING).min(1.);
+    for (position, mut transform) in query.iter_mut() {
+        let current = transform.translation.truncate();
+        if current != position.0 {
+            let p = current.lerp(position.0, t);
+            transform.translation.x = p.x;
+            transform.translation.y = p.y;
+        }
+    }
+}
@@ -0,0 +1,408 @@
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, SystemTime};
+
+use bevy::app::ScheduleRunnerSettings;
+use bevy::core::FixedTimestep;
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use bevy_ecs_tilemap::TilePos;
+use renet::{RenetConnectionConfig, RenetServer, ServerConfig, ServerEvent};
+
+use super::{
+    map_hash, ClientMessage, MapInfo, NetPlayer, PlayerState, ServerMessage, Snapshot, PRIVATE_KEY,
+    PROTOCOL_ID, RELIABLE_CHANNEL, UNRELIABLE_CHANNEL,
+};
+use crate::map_file::{map_path, MapFile};
+use crate::movement::{MovementSpeed, TileMover};
+use crate::pathfinding::{
+    build_nav_grid, pathfinding, receive_paths, Destination, NavGrid, PathFound, PathNotFound,
+    PathfindingSettings, TilePath,
+};
+use crate::picking::tile_to_world;
+use crate::sprite::{Animation, Facing};
+use crate::tiles::{MapBounds, MapLayer};
+use crate::traversal::{TilesetTraversal, Traversal};
+
+const MAX_CLIENTS: usize = 16;
+const SNAPSHOTS_PER_SECOND: f64 = 20.;
+/// Size of the map served when no map file is given
+const OPEN_MAP_SIZE: u32 = 64;
+/// Character every client plays as
+const NET_CHARACTER: &str = "basic";
+
+/// A headless app that owns the map and every character's movement, and
+/// serves clients on `addr`. Port 0 picks a free port; see
+/// `RenetServer::addr` for the one in use.
+pub fn server_app(addr: SocketAddr, map: Option<&str>) -> App {
+    let (grid, info, spawn) = match map.and_then(load_map) {
+        Some(map) => map,
+        None => (
+            NavGrid::open(OPEN_MAP_SIZE, OPEN_MAP_SIZE),
+            MapInfo {
+                name: None,
+                size: [OPEN_MAP_SIZE, OPEN_MAP_SIZE],
+                hash: 0,
+            },
+            TilePos(0, 0),
+        ),
+    };
+    let bounds = MapBounds {
+        width: info.size[0],
+        height: info.size[1],
+    };
+
+    let mut app = App::new();
+    app.insert_resource(ScheduleRunnerSettings::run_loop(Duration::from_secs_f64(
+        1. / 60.,
+    )))
+    .add_plugins(MinimalPlugins)
+    .add_plugin(TransformPlugin)
+    .add_event::<ServerEvent>()
+    .insert_resource(new_renet_server(addr))
+    .insert_resource(grid)
+    .insert_resource(bounds)
+    .insert_resource(info)
+    .insert_resource(SpawnTile(spawn))
+    .init_resource::<PathfindingSettings>()
+    .init_resource::<ClientPlayers>()
+    .add_event::<PathFound>()
+    .add_event::<PathNotFound>()
+    .add_plugin(crate::movement::Plugin)
+    .add_system_to_stage(CoreStage::PreUpdate, update_server)
+    .add_system_to_stage(CoreStage::PostUpdate, send_server_packets)
+    .add_system(pathfinding)
+    .add_system(receive_paths)
+    .add_system(client_connections)
+    .add_system(receive_client_messages)
+    .add_stage_after(
+        CoreStage::Update,
+        "net_snapshot",
+        SystemStage::parallel()
+            .with_run_criteria(FixedTimestep::steps_per_second(SNAPSHOTS_PER_SECOND))
+            .with_system(send_snapshots),
+    );
+    app
+}
+
+fn new_renet_server(addr: SocketAddr) -> RenetServer {
+    let socket = UdpSocket::bind(addr).expect("bind server socket");
+    // Connect tokens name the address clients reach us on
+    let addr = socket.local_addr().expect("server socket address");
+    let config = ServerConfig::new(MAX_CLIENTS, PROTOCOL_ID, addr, *PRIVATE_KEY);
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("system time");
+    RenetServer::new(now, config, RenetConnectionConfig::default(), socket)
+        .expect("start renet server")
+}
+
+/// Reads packets from clients and passes on connects and disconnects
+fn update_server(
+    time: Res<Time>,
+    mut server: ResMut<RenetServer>,
+    mut events: EventWriter<ServerEvent>,
+) {
+    if let Err(e) = server.update(time.delta()) {
+        warn!("receiving from clients: {}", e);
+    }
+    while let Some(event) = server.get_event() {
+        events.send(event);
+    }
+}
+
+/// Sends the messages queued this frame
+fn send_server_packets(mut server: ResMut<RenetServer>) {
+    if let Err(e) = server.send_packets() {
+        warn!("sending to clients: {}", e);
+    }
+}
+
+/// Reads a saved map and builds its `NavGrid` the way the game does
+fn load_map(name: &str) -> Option<(NavGrid, MapInfo, TilePos)> {
+    let assets = std::path::Path::new("assets");
+    let bytes = std::fs::read(assets.join(map_path(name)))
+        .map_err(|e| warn!("reading map {}: {}", name, e))
+        .ok()?;
+    let file = MapFile::from_slice(&bytes)
+        .map_err(|e| warn!("parsing map {}: {}", name, e))
+        .ok()?;
+    let traversal: Option<TilesetTraversal> =
+        std::fs::read(assets.join("tilesets/tileset.traversal.ron"))
+            .ok()
+            .and_then(|bytes| ron::de::from_bytes(&bytes).ok());
+
+    let [width, height] = file.size;
+    let traversal = traversal.as_ref();
+    let tiles = file
+        .layers
+        .iter()
+        .filter_map(|l| MapLayer::from_id(l.layer).map(|layer| (layer, l)))
+        .flat_map(|(layer, l)| {
+            l.tiles.iter().map(move |tile| {
+                let t = match traversal {
+                    Some(traversal) if traversal.tileset == tile.tileset => {
+                        traversal.get(&tile.group)
+                    }
+                    _ => Traversal::default(),
+                };
+                (TilePos(tile.pos[0], tile.pos[1]), layer, t)
+            })
+        });
+    let grid = build_nav_grid(width, height, tiles);
+    let spawn = file
+        .spawn_points
+        .iter()
+        .map(|s| TilePos(s.pos[0], s.pos[1]))
+        .chain((0..width * height).map(|i| TilePos(i % width, i / width)))
+        .find(|pos| grid.step_cost(*pos).is_some())
+        .unwrap_or(TilePos(0, 0));
+
+    let info = MapInfo {
+        name: Some(name.to_string()),
+        size: [width, height],
+        hash: map_hash(&bytes),
+    };
+    Some((grid, info, spawn))
+}
+
+/// Where new players appear
+struct SpawnTile(TilePos);
+
+/// The character of each connected client
+#[derive(Default)]
+struct ClientPlayers(HashMap<u64, Entity>);
+
+fn client_connections(
+    mut events: EventReader<ServerEvent>,
+    mut server: ResMut<RenetServer>,
+    mut players: ResMut<ClientPlayers>,
+    map: Res<MapInfo>,
+    spawn: Res<SpawnTile>,
+    mut commands: Commands,
+) {
+    for event in events.iter() {
+        match event {
+            ServerEvent::ClientConnected(id, _) => {
+                info!("client {} connected", id);
+                let e = commands
+                    .spawn()
+                    .insert(NetPlayer { id: *id })
+                    .insert(Transform::from_translation(
+                        tile_to_world(spawn.0).extend(0.),
+                    ))
+                    .insert(GlobalTransform::default())
+                    .insert(MovementSpeed::default())
+                    .insert(TileMover::new(spawn.0))
+                    .insert(Facing::default())
+                    .id();
+                players.0.insert(*id, e);
+
+                let welcome = ServerMessage::Welcome {
+                    id: *id,
+                    map: map.clone(),
+                };
+                if let Ok(message) = bincode::serialize(&welcome) {
+                    if let Err(e) = server.send_message(*id, RELIABLE_CHANNEL, message) {
+                        warn!("welcoming client {}: {}", id, e);
+                    }
+                }
+            }
+            ServerEvent::ClientDisconnected(id) => {
+                info!("client {} disconnected", id);
+                if let Some(e) = players.0.remove(id) {
+                    commands.entity(e).despawn_recursive();
+                }
+                if let Ok(message) = bincode::serialize(&ServerMessage::PlayerLeft { id: *id }) {
+                    server.broadcast_message(RELIABLE_CHANNEL, message);
+                }
+            }
+        }
+    }
+}
+
+/// Turns click-to-move requests into `Destination`s and passes one-shot
+/// animations on to every client. Paths start where the character's current
+/// step ends.
+fn receive_client_messages(
+    mut server: ResMut<RenetServer>,
+    players: Res<ClientPlayers>,
+    grid: Res<NavGrid>,
+    movers: Query<&TileMover>,
+    mut commands: Commands,
+) {
+    for id in server.clients_id() {
+        while let Some(message) = server.receive_message(id, RELIABLE_CHANNEL) {
+            let message: ClientMessage = match bincode::deserialize(&message) {
+                Ok(message) => message,
+                Err(e) => {
+                    warn!("bad message from client {}: {}", id, e);
+                    continue;
+                }
+            };
+            let e = match players.0.get(&id) {
+                Some(e) => *e,
+                None => continue,
+            };
+
+            match message {
+                ClientMessage::MoveTo { goal } => {
+                    let goal = TilePos(goal[0], goal[1]);
+                    if !grid.contains(goal) {
+                        continue;
+                    }
+                    if let Ok(mover) = movers.get(e) {
+                        commands
+                            .entity(e)
+                            .remove::<TilePath>()
+                            .insert(Destination::new(mover.next_tile(), goal));
+                    }
+                }
+                ClientMessage::Play { animation } => {
+                    if animation.repeats() {
+                        continue;
+                    }
+                    let play = ServerMessage::Play { id, animation };
+                    if let Ok(message) = bincode::serialize(&play) {
+                        server.broadcast_message(RELIABLE_CHANNEL, message);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn send_snapshots(
+    mut server: ResMut<RenetServer>,
+    query: Query<(
+        &NetPlayer,
+        &Transform,
+        &TileMover,
+        &Facing,
+        Option<&TilePath>,
+    )>,
+) {
+    let players = query
+        .iter()
+        .map(|(player, t, mover, facing, path)| PlayerState {
+            id: player.id,
+            character: NET_CHARACTER.to_string(),
+            position: [t.translation.x, t.translation.y],
+            facing: *facing,
+            animation: if path.is_some() || mover.is_stepping() {
+                Animation::Walk
+            } else {
+                Animation::Idle
+            },
+        })
+        .collect();
+
+    if let Ok(message) = bincode::serialize(&Snapshot { players }) {
+        server.broadcast_message(UNRELIABLE_CHANNEL, message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::thread;
+
+    use super::*;
+    use crate::net::client::new_renet_client;
+
+    #[test]
+    fn a_client_walks_its_character_on_a_headless_server() {
+        let mut server = server_app(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)), None);
+        let addr = server.world.get_resource::<RenetServer>().unwrap().addr();
+        let (mut client, id) = new_renet_client(addr);
+
+        let frame = Duration::from_millis(10);
+        let goal = TilePos(2, 0);
+        let mut welcomed = false;
+        // Five seconds of frames
+        for _ in 0..500 {
+            server.update();
+            client.update(frame).unwrap();
+
+            while let Some(message) = client.receive_message(RELIABLE_CHANNEL) {
+                if let Ok(ServerMessage::Welcome {
+                    id: welcome_id,
+                    map,
+                }) = bincode::deserialize(&message)
+                {
+                    assert_eq!(welcome_id, id);
+                    assert_eq!(
+                        map,
+                        MapInfo {
+                            name: None,
+                            size: [OPEN_MAP_SIZE, OPEN_MAP_SIZE],
+                            hash: 0,
+                        }
+                    );
+                    welcomed = true;
+
+                    let move_to = ClientMessage::MoveTo {
+                        goal: [goal.0, goal.1],
+                    };
+                    client
+                        .send_message(RELIABLE_CHANNEL, bincode::serialize(&move_to).unwrap())
+                        .unwrap();
+                }
+            }
+
+            while let Some(message) = client.receive_message(UNRELIABLE_CHANNEL) {
+                let snapshot: Snapshot = bincode::deserialize(&message).unwrap();
+                let arrived = snapshot
+                    .players
+                    .iter()
+                    .any(|p| p.id == id && Vec2::from(p.position) == tile_to_world(goal));
+                if arrived {
+                    return;
+                }
+            }
+
+            client.send_packets().unwrap();
+            thread::sleep(frame);
+        }
+        panic!("welcomed: {}, but never reached {:?}", welcomed, goal);
+    }
+
+    #[test]
+    fn one_shots_are_passed_on_to_clients() {
+        let mut server = server_app(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)), None);
+        let addr = server.world.get_resource::<RenetServer>().unwrap().addr();
+        let (mut client, id) = new_renet_client(addr);
+
+        let frame = Duration::from_millis(10);
+        for _ in 0..500 {
+            server.update();
+            client.update(frame).unwrap();
+
+            while let Some(message) = client.receive_message(RELIABLE_CHANNEL) {
+                match bincode::deserialize(&message).unwrap() {
+                    ServerMessage::Welcome { .. } => {
+                        // Looping animations follow movement, so only the jump
+                        // comes back
+                        for animation in [Animation::Dance, Animation::Jump] {
+                            let play = ClientMessage::Play { animation };
+                            client
+                                .send_message(RELIABLE_CHANNEL, bincode::serialize(&play).unwrap())
+                                .unwrap();
+                        }
+                    }
+                    ServerMessage::Play {
+                        id: play_id,
+                        animation,
+                    } => {
+                        assert_eq!((play_id, animation), (id, Animation::Jump));
+                        return;
+                    }
+                    ServerMessage::PlayerLeft { .. } => {}
+                }
+            }
+
+            client.send_packets().unwrap();
+            thread::sleep(frame);
+        }
+        panic!("the jump never came back");
+    }
+}
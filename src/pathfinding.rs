@@ -1,13 +1,29 @@
-use bevy::ecs::query::QueryEntityError;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::Arc;
+
 use bevy::prelude::Plugin as BevyPlugin;
-use bevy::{prelude::*, utils::HashMap};
-use bevy_ecs_tilemap::{MapQuery, Tile, TilePos};
-use bevy_tileset_map::prelude::{TileId, TilePlacer, Tilesets};
+use bevy::prelude::*;
+use bevy::tasks::{AsyncComputeTaskPool, Task};
+use bevy::utils::{HashMap, HashSet};
+use bevy_ecs_tilemap::{MapQuery, Tile, TileParent, TilePos};
+use bevy_tileset_map::prelude::{TileId, TilePlacer, Tileset, Tilesets};
+use futures_lite::future;
+
+use crate::tiles::{MapBounds, MapLayer, MAP_ID};
+use crate::traversal::{TerrainTraversal, TilesetTraversal, Traversal, BASE_STEP_COST};
 
 pub struct Plugin;
 impl BevyPlugin for Plugin {
     fn build(&self, app: &mut App) {
-        app.add_system(pathfinding).add_system(path_highlight);
+        app.add_event::<PathFound>()
+            .add_event::<PathNotFound>()
+            .init_resource::<NavGrid>()
+            .init_resource::<PathfindingSettings>()
+            .add_system(update_nav_grid)
+            .add_system(pathfinding)
+            .add_system(receive_paths)
+            .add_system(path_highlight);
     }
 }
 
@@ -26,12 +42,36 @@ impl Destination {
     }
 }
 
+/// Sent when a `Destination` request produced a `TilePath`
+#[derive(Debug, Clone, PartialEq)]
+pub struct PathFound {
+    pub entity: Entity,
+    pub destination: Destination,
+}
+
+/// Sent when a `Destination` request could not be satisfied
+#[derive(Debug, Clone, PartialEq)]
+pub struct PathNotFound {
+    pub entity: Entity,
+    pub destination: Destination,
+    pub reason: PathError,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathError {
+    /// The start or goal is not a tile on the map
+    OutOfBounds,
+    /// The goal tile can't be entered
+    Blocked,
+    /// Every reachable tile was searched without finding the goal
+    Unreachable,
+}
+
+/// Bookkeeping for a tile that has been reached by the search
 #[derive(Default, Debug, Clone, Copy, PartialEq)]
 pub struct Node {
     pub pos: TilePos,
     pub score: u32,
-    pub heuristic_score: u32,
-    pub visited: bool,
     pub previous_pos: Option<TilePos>,
 }
 
@@ -40,169 +80,853 @@ impl Node {
         Node {
             pos,
             score: u32::MAX,
-            heuristic_score: u32::MAX,
-            visited: false,
             previous_pos: None,
         }
     }
 }
 
+/// An entry in the open set, ordered so that `BinaryHeap` pops the lowest
+/// `heuristic_score` first
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct OpenNode {
+    pos: TilePos,
+    score: u32,
+    heuristic_score: u32,
+}
+
+impl Ord for OpenNode {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .heuristic_score
+            .cmp(&self.heuristic_score)
+            // Prefer nodes further along the path when estimates tie
+            .then_with(|| self.score.cmp(&other.score))
+            .then_with(|| (self.pos.0, self.pos.1).cmp(&(other.pos.0, other.pos.1)))
+    }
+}
+
+impl PartialOrd for OpenNode {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Tracks which tiles each entity's `TilePath` covers and how many paths
+/// cover each tile, so highlights only change where a path did
+#[derive(Default)]
+pub struct PathHighlightState {
+    paths: HashMap<Entity, HashSet<TilePos>>,
+    counts: HashMap<TilePos, u32>,
+}
+
+/// Marks every tile in a `TilePath` on the overlay layer, leaving the
+/// terrain underneath untouched. Only tiles whose path membership changed
+/// are touched, unless the map was rebuilt without its highlights.
 pub fn path_highlight(
-    tiles: Query<(&Tile, &TilePos)>,
-    tile_paths: Query<&TilePath>,
+    changed_paths: Query<(Entity, &TilePath), Changed<TilePath>>,
+    paths: Query<(Entity, &TilePath)>,
+    removed_paths: RemovedComponents<TilePath>,
+    bounds: Option<Res<MapBounds>>,
+    mut state: Local<PathHighlightState>,
     mut placer: TilePlacer,
     tilesets: Tilesets,
 ) {
-    let mut tile_map = HashMap::<&TilePos, bool>::default();
+    let overlay = MapLayer::Overlay.id();
+    let mut added = Vec::new();
+    let mut removed = Vec::new();
 
-    // Make a lookup for every TilePos that is in a TilePath
-    for path in tile_paths.iter() {
-        for tp in path.0.iter() {
-            tile_map.insert(tp, true);
+    // Removals are only reported this frame, so they are read before
+    // anything can return early
+    for e in removed_paths.iter() {
+        if let Some(old) = state.paths.remove(&e) {
+            removed.extend(old);
         }
     }
 
-    if let Some(tileset) = tilesets.get_by_name("terrain") {
-        let tileset_id = tileset.id().clone();
+    // `spawn_map` inserts new bounds along with an empty overlay
+    let rebuilt = bounds.map_or(false, |b| b.is_changed());
+    if rebuilt {
+        *state = PathHighlightState::default();
+        removed.clear();
+    }
+
+    let highlight_id = tilesets.get_by_name("terrain").and_then(|tileset| {
+        tileset
+            .get_tile_group_id("sand")
+            .map(|id| TileId::new(*id, tileset.id().clone()))
+    });
 
-        if let (Some(highlight_id), Some(default_id)) = (
-            tileset.get_tile_group_id("sand"),
-            tileset.get_tile_group_id("dirt"),
-        ) {
-            for (_, tp) in tiles.iter() {
-                let id = if tile_map.contains_key(tp) {
-                    highlight_id
-                } else {
-                    default_id
-                };
-                placer
-                    .replace(TileId::new(*id, tileset_id), *tp, 0, 0)
-                    .err();
+    let changed: Vec<_> = if rebuilt {
+        paths.iter().collect()
+    } else {
+        changed_paths.iter().collect()
+    };
+    for (e, path) in changed {
+        let new: HashSet<TilePos> = path.0.iter().copied().collect();
+        let old = state.paths.remove(&e).unwrap_or_default();
+        added.extend(new.difference(&old).copied());
+        removed.extend(old.difference(&new).copied());
+        state.paths.insert(e, new);
+    }
+
+    for tp in added {
+        let count = state.counts.entry(tp).or_insert(0);
+        *count += 1;
+        if let (1, Some(id)) = (*count, highlight_id) {
+            placer.replace(id, tp, MAP_ID, overlay).err();
+        }
+    }
+
+    for tp in removed {
+        if let Some(count) = state.counts.get_mut(&tp) {
+            *count -= 1;
+            if *count == 0 {
+                state.counts.remove(&tp);
+                placer.remove(tp, MAP_ID, overlay).err();
+            }
+        }
+    }
+}
+
+/// Cost of a diagonal step onto a tile with a multiplier of `1.0`, roughly
+/// `BASE_STEP_COST * sqrt(2)`
+pub const DIAGONAL_STEP_COST: u32 = 14;
+
+/// Which steps a path may take between tiles
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Movement {
+    FourWay,
+    /// Four-way plus diagonals, with octile costs
+    EightWay,
+}
+
+impl Default for Movement {
+    fn default() -> Self {
+        Movement::EightWay
+    }
+}
+
+impl Movement {
+    /// An admissible estimate of the cost from `curr` to `target` for this
+    /// movement rule: Manhattan distance for four-way, octile for eight-way
+    pub fn heuristic(&self, curr: TilePos, target: TilePos) -> u32 {
+        let dx = curr.0.abs_diff(target.0);
+        let dy = curr.1.abs_diff(target.1);
+        match *self {
+            Movement::FourWay => (dx + dy) * BASE_STEP_COST,
+            Movement::EightWay => {
+                BASE_STEP_COST * (dx + dy) - (2 * BASE_STEP_COST - DIAGONAL_STEP_COST) * dx.min(dy)
+            }
+        }
+    }
+}
+
+#[derive(Default, Debug, Clone, Copy)]
+pub struct PathfindingSettings {
+    pub movement: Movement,
+}
+
+/// A snapshot of which tiles can be entered and at what cost, cheap to clone
+/// into pathfinding tasks
+#[derive(Default, Debug, Clone)]
+pub struct NavGrid {
+    width: u32,
+    height: u32,
+    costs: Arc<Vec<Option<u32>>>,
+}
+
+impl NavGrid {
+    pub fn new(width: u32, height: u32, costs: Vec<Option<u32>>) -> Self {
+        assert_eq!(costs.len(), (width * height) as usize, "NavGrid costs size");
+        NavGrid {
+            width,
+            height,
+            costs: Arc::new(costs),
+        }
+    }
+
+    /// A grid where every tile can be entered at the base cost
+    pub fn open(width: u32, height: u32) -> Self {
+        NavGrid::new(
+            width,
+            height,
+            vec![Some(BASE_STEP_COST); (width * height) as usize],
+        )
+    }
+
+    pub fn contains(&self, pos: TilePos) -> bool {
+        pos.0 < self.width && pos.1 < self.height
+    }
+
+    /// The cost of stepping onto `pos`, or `None` if it is blocked or off the map
+    pub fn step_cost(&self, pos: TilePos) -> Option<u32> {
+        if !self.contains(pos) {
+            return None;
+        }
+        self.costs[(pos.1 * self.width + pos.0) as usize]
+    }
+
+    /// Enterable neighbors of `pos` with the cost of stepping onto them.
+    ///
+    /// Diagonal steps are only allowed when both tiles they cut past are
+    /// enterable, so paths never squeeze between blocked corners.
+    pub fn neighbors(
+        &self,
+        pos: TilePos,
+        movement: Movement,
+    ) -> impl Iterator<Item = (TilePos, u32)> + '_ {
+        const OFFSETS: [(i64, i64); 8] = [
+            (0, 1),
+            (1, 0),
+            (0, -1),
+            (-1, 0),
+            (1, 1),
+            (1, -1),
+            (-1, -1),
+            (-1, 1),
+        ];
+        let count = match movement {
+            Movement::FourWay => 4,
+            Movement::EightWay => 8,
+        };
+
+        OFFSETS[..count].iter().filter_map(move |&(dx, dy)| {
+            let tp = self.offset(pos, dx, dy)?;
+            let cost = self.step_cost(tp)?;
+
+            if dx == 0 || dy == 0 {
+                return Some((tp, cost));
             }
+
+            self.offset(pos, dx, 0).and_then(|p| self.step_cost(p))?;
+            self.offset(pos, 0, dy).and_then(|p| self.step_cost(p))?;
+            Some((tp, cost * DIAGONAL_STEP_COST / BASE_STEP_COST))
+        })
+    }
+
+    fn offset(&self, pos: TilePos, dx: i64, dy: i64) -> Option<TilePos> {
+        let x = pos.0 as i64 + dx;
+        let y = pos.1 as i64 + dy;
+        if x < 0 || y < 0 {
+            return None;
+        }
+        Some(TilePos(x as u32, y as u32)).filter(|tp| self.contains(*tp))
+    }
+}
+
+/// An in-flight path computation for a `Destination` request. Replacing or
+/// removing this component drops the task, discarding its result.
+#[derive(Component)]
+pub struct PathTask {
+    destination: Destination,
+    task: Task<Result<Vec<TilePos>, PathError>>,
+}
+
+/// Rebuilds the `NavGrid` whenever tiles, tilesets or traversal data change
+#[allow(clippy::too_many_arguments)]
+fn update_nav_grid(
+    changed_tiles: Query<(Entity, &TileParent), Changed<Tile>>,
+    removed_tiles: RemovedComponents<Tile>,
+    mut walkability_tiles: Local<HashSet<Entity>>,
+    mut traversal_events: EventReader<AssetEvent<TilesetTraversal>>,
+    mut tileset_events: EventReader<AssetEvent<Tileset>>,
+    tile_query: Query<(&TilePos, &Tile, &TileParent)>,
+    mut map_query: MapQuery,
+    tilesets: Tilesets,
+    traversals: Res<Assets<TilesetTraversal>>,
+    terrain_traversal: Res<TerrainTraversal>,
+    mut nav_grid: ResMut<NavGrid>,
+) {
+    let traversal_changed = traversal_events.iter().count() > 0;
+    // A reloaded tileset can move groups to other texture indices
+    let tileset_changed = tileset_events.iter().count() > 0;
+
+    // Removed tiles no longer have a `TileParent`, so remember which ones
+    // were on layers that matter; overlay churn then never rebuilds the grid
+    let mut tiles_changed = false;
+    for (e, parent) in changed_tiles.iter() {
+        if parent.map_id == MAP_ID
+            && MapLayer::from_id(parent.layer_id).map_or(false, |l| l.affects_walkability())
+        {
+            walkability_tiles.insert(e);
+            tiles_changed = true;
+        }
+    }
+    for e in removed_tiles.iter() {
+        tiles_changed |= walkability_tiles.remove(&e);
+    }
+    if !tiles_changed && !traversal_changed && !tileset_changed {
+        return;
+    }
+
+    let settings = match map_query.get_layer(MAP_ID, MapLayer::Ground.id()) {
+        Some((_, layer)) => &layer.settings,
+        None => return,
+    };
+    let width = settings.map_size.0 * settings.chunk_size.0;
+    let height = settings.map_size.1 * settings.chunk_size.1;
+
+    // Tiles without traversal data are walkable at the base cost
+    let costs = match (
+        tilesets.get_by_name("terrain"),
+        terrain_traversal
+            .handle
+            .as_ref()
+            .and_then(|h| traversals.get(h)),
+    ) {
+        (Some(tileset), Some(traversal)) => traversal.by_texture_index(tileset),
+        _ => HashMap::default(),
+    };
+
+    let tiles = tile_query
+        .iter()
+        .filter(|(_, _, parent)| parent.map_id == MAP_ID)
+        .filter_map(|(tp, tile, parent)| {
+            let traversal = costs.get(&tile.texture_index).copied().unwrap_or_default();
+            MapLayer::from_id(parent.layer_id).map(|layer| (*tp, layer, traversal))
+        });
+    *nav_grid = build_nav_grid(width, height, tiles);
+}
+
+/// Builds the `NavGrid` of a `width` by `height` map from its tiles, each
+/// with the layer it is on and the traversal of its tile group.
+///
+/// A tile can be entered if the ground layer has a tile there and no
+/// layer above it blocks it. Its cost is the highest cost of all layers.
+pub fn build_nav_grid(
+    width: u32,
+    height: u32,
+    tiles: impl IntoIterator<Item = (TilePos, MapLayer, Traversal)>,
+) -> NavGrid {
+    let mut has_ground = vec![false; (width * height) as usize];
+    let mut grid = vec![Some(BASE_STEP_COST); (width * height) as usize];
+    for (tp, layer, traversal) in tiles {
+        if !layer.affects_walkability() || tp.0 >= width || tp.1 >= height {
+            continue;
+        }
+
+        let i = (tp.1 * width + tp.0) as usize;
+        let traversal = match layer {
+            MapLayer::Obstacles => Traversal::Blocked,
+            _ => traversal,
+        };
+        if layer == MapLayer::Ground {
+            has_ground[i] = true;
+        }
+        grid[i] = grid[i].zip(traversal.step_cost()).map(|(a, b)| a.max(b));
+    }
+
+    for (cost, ground) in grid.iter_mut().zip(has_ground) {
+        if !ground {
+            *cost = None;
         }
     }
+
+    NavGrid::new(width, height, grid)
 }
 
+/// Turns `Destination` requests into `PathTask`s on the async compute pool
 pub fn pathfinding(
     query: Query<(Entity, &Destination)>,
-    tile_query: Query<(Entity, &Tile, &TilePos)>,
-    mut map_query: MapQuery,
+    nav_grid: Res<NavGrid>,
+    settings: Res<PathfindingSettings>,
+    thread_pool: Res<AsyncComputeTaskPool>,
     mut commands: Commands,
 ) {
     for (e, d) in query.iter() {
-        if map_query.get_tile_entity(d.goal, 0, 0).is_err()
-            || map_query.get_tile_entity(d.start, 0, 0).is_err()
-        {
-            commands.entity(e).remove::<Destination>();
+        let grid = nav_grid.clone();
+        let (start, goal) = (d.start, d.goal);
+        let movement = settings.movement;
+
+        let task = thread_pool.spawn(async move { plan_path(&grid, start, goal, movement) });
+
+        // Inserting over an older `PathTask` drops it, so a stale result
+        // can never be applied
+        commands.entity(e).remove::<Destination>().insert(PathTask {
+            destination: d.clone(),
+            task,
+        });
+    }
+}
+
+/// Turns finished `PathTask`s into a `TilePath` and reports the result.
+/// Entities with a newer `Destination` pending are skipped; their task is
+/// about to be replaced.
+pub fn receive_paths(
+    mut query: Query<(Entity, &mut PathTask), Without<Destination>>,
+    mut path_found: EventWriter<PathFound>,
+    mut path_not_found: EventWriter<PathNotFound>,
+    mut commands: Commands,
+) {
+    for (e, mut path_task) in query.iter_mut() {
+        let result = match future::block_on(future::poll_once(&mut path_task.task)) {
+            Some(result) => result,
+            None => continue,
+        };
+
+        commands.entity(e).remove::<PathTask>();
+
+        let destination = path_task.destination.clone();
+        match result {
+            Ok(v) => {
+                commands.entity(e).insert(TilePath(v));
+                path_found.send(PathFound {
+                    entity: e,
+                    destination,
+                });
+            }
+            Err(reason) => path_not_found.send(PathNotFound {
+                entity: e,
+                destination,
+                reason,
+            }),
+        }
+    }
+}
+
+/// Finds a path over `grid`, or why there is none. The path is ordered as
+/// `find_path` returns it.
+pub fn plan_path(
+    grid: &NavGrid,
+    start: TilePos,
+    goal: TilePos,
+    movement: Movement,
+) -> Result<Vec<TilePos>, PathError> {
+    if !grid.contains(start) || !grid.contains(goal) {
+        Err(PathError::OutOfBounds)
+    } else if start != goal && grid.step_cost(goal).is_none() {
+        Err(PathError::Blocked)
+    } else {
+        find_path(start, goal, movement, |pos| grid.neighbors(pos, movement))
+            .ok_or(PathError::Unreachable)
+    }
+}
+
+/// A* search from `start` to `goal`.
+///
+/// `neighbors` yields each tile reachable from the given tile along with the
+/// cost of stepping onto it, and `movement` picks the matching heuristic.
+/// Nodes are only created once they are reached, so the cost of a search
+/// depends on the area explored rather than the map size.
+///
+/// The returned path is ordered from `goal` back to the tile after `start`,
+/// ready to be consumed with `Vec::pop`. It is empty when `start == goal`.
+pub fn find_path<N, I>(
+    start: TilePos,
+    goal: TilePos,
+    movement: Movement,
+    mut neighbors: N,
+) -> Option<Vec<TilePos>>
+where
+    N: FnMut(TilePos) -> I,
+    I: IntoIterator<Item = (TilePos, u32)>,
+{
+    if start == goal {
+        return Some(Vec::new());
+    }
+
+    let mut graph: HashMap<TilePos, Node> = HashMap::default();
+    let mut closed: HashSet<TilePos> = HashSet::default();
+    let mut open = BinaryHeap::new();
+
+    graph.insert(
+        start,
+        Node {
+            pos: start,
+            score: 0,
+            previous_pos: None,
+        },
+    );
+    open.push(OpenNode {
+        pos: start,
+        score: 0,
+        heuristic_score: movement.heuristic(start, goal),
+    });
+
+    while let Some(curr) = open.pop() {
+        // Stale entry for a node that was already reached more cheaply
+        if !closed.insert(curr.pos) {
             continue;
         }
 
-        let mut graph: HashMap<TilePos, Node> = HashMap::default();
+        if curr.pos == goal {
+            let mut v: Vec<TilePos> = Vec::new();
+            v.push(curr.pos);
 
-        // Init Graph
-        graph.insert(
-            d.start,
-            Node {
-                pos: d.start,
-                score: 0,
-                heuristic_score: 0,
-                ..Default::default()
-            },
-        );
+            let mut otp = graph.get(&curr.pos).and_then(|n| n.previous_pos);
+            while let Some(tp) = otp {
+                if tp != start {
+                    v.push(tp);
+                }
 
-        for (_, _, tp) in tile_query.iter() {
-            if *tp != d.start && !graph.contains_key(tp) {
-                let n = Node::new(*tp);
-                graph.insert(*tp, n);
+                otp = graph.get(&tp).expect("previous_pos node").previous_pos;
             }
-        }
 
-        loop {
-            // Find first best
-            let curr_node = graph
-                .values()
-                .into_iter()
-                .fold(Node::new(TilePos(0, 0)), |acc, item| {
-                    if !item.visited && item.heuristic_score < acc.heuristic_score {
-                        *item
-                    } else {
-                        acc
-                    }
-                })
-                .clone();
+            return Some(v);
+        }
 
-            if curr_node.pos == TilePos(0, 0) && curr_node.score == u32::MAX {
-                // No path found, bail
-                commands.entity(e).remove::<Destination>();
+        for (tp, cost) in neighbors(curr.pos) {
+            // Ignore tiles we have already checked
+            if closed.contains(&tp) {
                 continue;
             }
 
-            // Set to visited
-            graph
-                .get_mut(&curr_node.pos)
-                .expect("set curr_node visited")
-                .visited = true;
-
-            // Get neighbor tile entities
-            for e in map_query
-                .get_tile_neighbors(curr_node.pos, 0, 0)
-                .iter()
-                .filter_map(|n| n.ok())
-            {
-                // Check the node for that tile
-                let tp = tile_query.get(e);
-                if let Err(QueryEntityError::NoSuchEntity) = tp {
-                    info!("No Such entity {:?}", curr_node);
-                    continue;
-                }
-                let tp = tile_query.get(e).expect("neighbor node").2.clone();
-                if tp == curr_node.pos {
-                    continue;
-                }
+            let new_score = calculate_score(curr.score, cost);
+            let nn = graph.entry(tp).or_insert_with(|| Node::new(tp));
 
-                if let Some(mut nn) = graph.get_mut(&tp) {
-                    // Ignore tiles we have already checked
-                    if nn.visited == false {
-                        // Calc score based on current node
-                        let new_score = calculate_score(&curr_node);
-                        // Update is shorter
-                        if new_score < nn.score {
-                            nn.score = new_score;
-                            nn.heuristic_score =
-                                new_score + calculate_heuristic_score(nn.pos, d.goal);
-                            nn.previous_pos = Some(curr_node.pos);
-                        }
-                    }
-                }
+            // Update if shorter
+            if new_score < nn.score {
+                nn.score = new_score;
+                nn.previous_pos = Some(curr.pos);
+                open.push(OpenNode {
+                    pos: tp,
+                    score: new_score,
+                    heuristic_score: new_score + movement.heuristic(tp, goal),
+                });
             }
-            if curr_node.pos == d.goal {
-                let mut v: Vec<TilePos> = Vec::new();
-                v.push(curr_node.pos);
+        }
+    }
+
+    None
+}
+
+fn calculate_score(curr_score: u32, step_cost: u32) -> u32 {
+    curr_score.saturating_add(step_cost)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::tasks::TaskPool;
+
+    extern crate test;
+
+    /// A grid from rows of `.` (open) and `#` (blocked), top row first
+    fn grid(rows: &[&str]) -> NavGrid {
+        let width = rows[0].len() as u32;
+        let costs = rows
+            .iter()
+            .flat_map(|row| row.chars())
+            .map(|c| (c == '.').then(|| BASE_STEP_COST))
+            .collect();
+        NavGrid::new(width, rows.len() as u32, costs)
+    }
+
+    #[test]
+    fn start_is_goal() {
+        let grid = grid(&["..", ".."]);
+        let movement = Movement::EightWay;
+        let path = find_path(TilePos(1, 1), TilePos(1, 1), movement, |pos| {
+            grid.neighbors(pos, movement)
+        });
+        assert_eq!(path, Some(vec![]));
+        assert_eq!(
+            plan_path(&grid, TilePos(1, 1), TilePos(1, 1), movement),
+            Ok(vec![])
+        );
+    }
+
+    #[test]
+    fn out_of_bounds() {
+        let grid = grid(&["..", ".."]);
+        for (start, goal) in [
+            (TilePos(0, 0), TilePos(2, 0)),
+            (TilePos(0, 5), TilePos(0, 0)),
+        ] {
+            assert_eq!(
+                plan_path(&grid, start, goal, Movement::EightWay),
+                Err(PathError::OutOfBounds)
+            );
+        }
+    }
+
+    #[test]
+    fn blocked_goal() {
+        let grid = grid(&["..", ".#"]);
+        assert_eq!(
+            plan_path(&grid, TilePos(0, 0), TilePos(1, 1), Movement::EightWay),
+            Err(PathError::Blocked)
+        );
+    }
+
+    #[test]
+    fn unreachable_goal() {
+        let grid = grid(&[
+            "..#..", //
+            "..#..", //
+            "..#..",
+        ]);
+        assert_eq!(
+            plan_path(&grid, TilePos(0, 0), TilePos(4, 2), Movement::EightWay),
+            Err(PathError::Unreachable)
+        );
+    }
+
+    #[test]
+    fn path_runs_from_goal_back_to_after_start() {
+        let grid = grid(&["...."]);
+        assert_eq!(
+            plan_path(&grid, TilePos(0, 0), TilePos(3, 0), Movement::FourWay),
+            Ok(vec![TilePos(3, 0), TilePos(2, 0), TilePos(1, 0)])
+        );
+    }
+
+    #[test]
+    fn diagonals_do_not_cut_blocked_corners() {
+        let grid = grid(&[
+            ".#", //
+            "..",
+        ]);
+        assert_eq!(
+            plan_path(&grid, TilePos(0, 0), TilePos(1, 1), Movement::EightWay),
+            Ok(vec![TilePos(1, 1), TilePos(0, 1)])
+        );
+    }
+
+    #[test]
+    fn nav_grid_takes_the_highest_cost_over_ground() {
+        let grid = build_nav_grid(
+            3,
+            1,
+            [
+                (TilePos(0, 0), MapLayer::Ground, Traversal::Cost(1.5)),
+                (TilePos(0, 0), MapLayer::Decoration, Traversal::Cost(3.)),
+                (TilePos(0, 0), MapLayer::Overlay, Traversal::Blocked),
+                (TilePos(1, 0), MapLayer::Ground, Traversal::default()),
+                (TilePos(1, 0), MapLayer::Obstacles, Traversal::default()),
+                // No ground under it
+                (TilePos(2, 0), MapLayer::Decoration, Traversal::default()),
+                (TilePos(5, 0), MapLayer::Ground, Traversal::default()),
+            ],
+        );
+        assert_eq!(grid.step_cost(TilePos(0, 0)), Some(3 * BASE_STEP_COST));
+        assert_eq!(grid.step_cost(TilePos(1, 0)), None);
+        assert_eq!(grid.step_cost(TilePos(2, 0)), None);
+    }
 
-                let mut otp = curr_node.previous_pos;
+    /// The search `find_path` replaced, kept as a baseline for the benches:
+    /// every tile gets a node up front and the next node to visit is found
+    /// by scanning all of them
+    fn linear_scan_path(
+        grid: &NavGrid,
+        start: TilePos,
+        goal: TilePos,
+        movement: Movement,
+    ) -> Option<Vec<TilePos>> {
+        #[derive(Clone, Copy)]
+        struct ScanNode {
+            score: u32,
+            heuristic_score: u32,
+            visited: bool,
+            previous_pos: Option<TilePos>,
+        }
+
+        let mut graph: HashMap<TilePos, ScanNode> = (0..grid.width * grid.height)
+            .map(|i| {
+                let node = ScanNode {
+                    score: u32::MAX,
+                    heuristic_score: u32::MAX,
+                    visited: false,
+                    previous_pos: None,
+                };
+                (TilePos(i % grid.width, i / grid.width), node)
+            })
+            .collect();
+        let first = graph.get_mut(&start)?;
+        first.score = 0;
+        first.heuristic_score = 0;
+
+        loop {
+            let (pos, curr) = graph
+                .iter()
+                .filter(|(_, n)| !n.visited && n.heuristic_score < u32::MAX)
+                .min_by_key(|(_, n)| n.heuristic_score)
+                .map(|(pos, n)| (*pos, *n))?;
+            graph.get_mut(&pos).expect("current node").visited = true;
+
+            if pos == goal {
+                let mut v = vec![pos];
+                let mut otp = curr.previous_pos;
                 while let Some(tp) = otp {
-                    if tp != d.start {
+                    if tp != start {
                         v.push(tp);
                     }
+                    otp = graph[&tp].previous_pos;
+                }
+                return Some(v);
+            }
 
-                    otp = graph.get(&tp).expect("previous_pos node").previous_pos;
+            for (tp, cost) in grid.neighbors(pos, movement) {
+                let nn = graph.get_mut(&tp).expect("every tile has a node");
+                let new_score = calculate_score(curr.score, cost);
+                if !nn.visited && new_score < nn.score {
+                    nn.score = new_score;
+                    nn.heuristic_score = new_score + movement.heuristic(tp, goal);
+                    nn.previous_pos = Some(pos);
                 }
+            }
+        }
+    }
+
+    /// The cost of walking `path`, as returned by `find_path`, from `start`
+    fn path_cost(grid: &NavGrid, start: TilePos, path: &[TilePos], movement: Movement) -> u32 {
+        let mut prev = start;
+        let mut cost = 0;
+        for tp in path.iter().rev() {
+            cost += grid
+                .neighbors(prev, movement)
+                .find(|(n, _)| n == tp)
+                .expect("path steps are neighbors")
+                .1;
+            prev = *tp;
+        }
+        cost
+    }
+
+    /// An app running the `Destination` to `TilePath` systems over `grid`
+    fn path_app(grid: NavGrid) -> App {
+        let mut app = App::new();
+        app.insert_resource(grid)
+            .init_resource::<PathfindingSettings>()
+            .insert_resource(AsyncComputeTaskPool(TaskPool::new()))
+            .add_event::<PathFound>()
+            .add_event::<PathNotFound>()
+            .add_system(pathfinding)
+            .add_system(receive_paths);
+        app
+    }
+
+    /// Sends a new entity from `start` to `goal` and runs `app` until its
+    /// path arrives
+    fn walk(app: &mut App, start: TilePos, goal: TilePos) -> TilePath {
+        let e = app.world.spawn().insert(Destination::new(start, goal)).id();
+        for _ in 0..100_000 {
+            app.update();
+            if let Some(path) = app.world.entity_mut(e).remove::<TilePath>() {
+                app.world.despawn(e);
+                return path;
+            }
+        }
+        panic!("no path from {:?} to {:?}", start, goal);
+    }
 
-                commands
-                    .entity(e)
-                    .remove::<Destination>()
-                    .insert(TilePath(v));
+    #[test]
+    fn destinations_become_tile_paths() {
+        let mut app = path_app(grid(&["...", "...", "..."]));
+        assert_eq!(
+            walk(&mut app, TilePos(0, 0), TilePos(2, 2)),
+            TilePath(vec![TilePos(2, 2), TilePos(1, 1)])
+        );
+    }
+
+    #[test]
+    fn a_replaced_destination_drops_the_stale_path() {
+        let mut app = path_app(serpentine_grid(64));
+        let (first, second) = (TilePos(63, 63), TilePos(2, 0));
+        let e = app
+            .world
+            .spawn()
+            .insert(Destination::new(TilePos(0, 0), first))
+            .id();
+        app.update();
+        assert!(app.world.get::<PathTask>(e).is_some());
 
+        app.world
+            .entity_mut(e)
+            .insert(Destination::new(TilePos(0, 0), second));
+        for _ in 0..100_000 {
+            app.update();
+            if let Some(path) = app.world.get::<TilePath>(e) {
+                assert_eq!(path.0.first(), Some(&second));
                 return;
             }
         }
+        panic!("no path to {:?}", second);
     }
-}
 
-fn calculate_heuristic_score(curr: TilePos, target: TilePos) -> u32 {
-    curr.0.abs_diff(target.0) + curr.1.abs_diff(target.1)
-}
+    #[test]
+    fn find_path_costs_match_the_linear_scan() {
+        let grid = serpentine_grid(64);
+        for movement in [Movement::FourWay, Movement::EightWay] {
+            for goal in [TilePos(63, 63), TilePos(20, 40), TilePos(63, 0)] {
+                let start = TilePos(0, 0);
+                let heap = find_path(start, goal, movement, |pos| grid.neighbors(pos, movement))
+                    .expect("heap path");
+                let scan = linear_scan_path(&grid, start, goal, movement).expect("scan path");
+                assert_eq!(
+                    path_cost(&grid, start, &heap, movement),
+                    path_cost(&grid, start, &scan, movement),
+                    "{:?} to {:?}",
+                    movement,
+                    goal
+                );
+            }
+        }
+    }
 
-fn calculate_score(curr: &Node) -> u32 {
-    curr.score + 1
+    /// A `size`x`size` grid walled off into columns with alternating gaps, so
+    /// the path from corner to corner has to wind through every column
+    fn serpentine_grid(size: u32) -> NavGrid {
+        let costs = (0..size * size)
+            .map(|i| {
+                let (x, y) = (i % size, i / size);
+                let wall = x % 16 == 8;
+                let gap = if (x / 16) % 2 == 0 {
+                    y == size - 1
+                } else {
+                    y == 0
+                };
+                if wall && !gap {
+                    None
+                } else {
+                    Some(BASE_STEP_COST)
+                }
+            })
+            .collect();
+        NavGrid::new(size, size, costs)
+    }
+
+    #[bench]
+    fn find_path_256_corner_to_corner(b: &mut test::Bencher) {
+        let grid = serpentine_grid(256);
+        let movement = Movement::EightWay;
+        b.iter(|| {
+            find_path(TilePos(0, 0), TilePos(255, 255), movement, |pos| {
+                grid.neighbors(pos, movement)
+            })
+            .expect("path through the gaps")
+        });
+    }
+
+    /// The 256x256 search through the systems, including task spawning and
+    /// polling
+    #[bench]
+    fn destination_to_tile_path_256(b: &mut test::Bencher) {
+        let mut app = path_app(serpentine_grid(256));
+        b.iter(|| walk(&mut app, TilePos(0, 0), TilePos(255, 255)));
+    }
+
+    // The linear scan is quadratic in the map size, so the comparison runs
+    // on a 64x64 grid
+
+    #[bench]
+    fn find_path_64_corner_to_corner(b: &mut test::Bencher) {
+        let grid = serpentine_grid(64);
+        let movement = Movement::EightWay;
+        b.iter(|| {
+            find_path(TilePos(0, 0), TilePos(63, 63), movement, |pos| {
+                grid.neighbors(pos, movement)
+            })
+            .expect("path through the gaps")
+        });
+    }
+
+    #[bench]
+    fn linear_scan_64_corner_to_corner(b: &mut test::Bencher) {
+        let grid = serpentine_grid(64);
+        b.iter(|| {
+            linear_scan_path(&grid, TilePos(0, 0), TilePos(63, 63), Movement::EightWay)
+                .expect("path through the gaps")
+        });
+    }
 }
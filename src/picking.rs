@@ -0,0 +1,353 @@
+use std::cmp::Ordering;
+
+use bevy::prelude::Plugin as BevyPlugin;
+use bevy::prelude::*;
+use bevy::render::camera::Camera;
+use bevy_ecs_tilemap::{Map, TilePos};
+use bevy_egui::EguiContext;
+
+use crate::camera::WorldCamera;
+use crate::input::{Action, ActionSystem};
+use crate::tiles::{MapBounds, MAP_ID, MAP_LIFT};
+use crate::utils::*;
+
+/// The cursor position in world space this frame and last frame
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub struct CursorWorld {
+    pub current: Option<Vec2>,
+    pub previous: Option<Vec2>,
+}
+
+/// The map tile under the cursor, and the one it was over before. Only
+/// changes when the cursor moves to another tile.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CursorTile {
+    pub current: Option<TilePos>,
+    pub previous: Option<TilePos>,
+}
+
+/// The cursor moved onto a tile
+#[derive(Debug, Clone, Copy)]
+pub struct TileHoverEntered(pub TilePos);
+
+/// The cursor moved off a tile
+#[derive(Debug, Clone, Copy)]
+pub struct TileHoverLeft(pub TilePos);
+
+/// Modifier keys held down, e.g. for `input::Binding::Chord`
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub ctrl: bool,
+    pub alt: bool,
+}
+
+impl Modifiers {
+    pub fn from_input(keys: &Input<KeyCode>) -> Self {
+        Modifiers {
+            shift: keys.pressed(KeyCode::LShift) || keys.pressed(KeyCode::RShift),
+            ctrl: keys.pressed(KeyCode::LControl) || keys.pressed(KeyCode::RControl),
+            alt: keys.pressed(KeyCode::LAlt) || keys.pressed(KeyCode::RAlt),
+        }
+    }
+}
+
+/// `Action::Interact` was pressed over a map tile. Presses over UI panels
+/// are not sent.
+#[derive(Debug, Clone, Copy)]
+pub struct TileClicked {
+    pub pos: TilePos,
+    /// The exact world position clicked
+    pub world: Vec2,
+    /// Whether `Action::Select` was held
+    pub select: bool,
+}
+
+/// Picking runs before `CoreStage::Update` so every system sees this frame's
+/// cursor
+#[derive(Debug, Clone, PartialEq, Eq, Hash, SystemLabel)]
+pub enum PickingSystem {
+    CursorWorld,
+    CursorTile,
+    HoveredEntity,
+    TileClicked,
+}
+
+/// An entity that can be hovered, with the size of its box centred on its
+/// translation
+#[derive(Component, Debug, Clone, Copy)]
+pub struct Pickable {
+    pub size: Vec2,
+}
+
+impl Pickable {
+    pub fn new(size: Vec2) -> Self {
+        Pickable { size }
+    }
+}
+
+/// The topmost `Pickable` under the cursor
+#[derive(Default, Debug, Clone, Copy)]
+pub struct HoveredEntity(pub Option<Entity>);
+
+pub struct Plugin;
+
+impl BevyPlugin for Plugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<CursorWorld>()
+            .init_resource::<CursorTile>()
+            .init_resource::<HoveredEntity>()
+            .add_event::<TileHoverEntered>()
+            .add_event::<TileHoverLeft>()
+            .add_event::<TileClicked>()
+            .add_system_to_stage(
+                CoreStage::PreUpdate,
+                update_cursor_world.label(PickingSystem::CursorWorld),
+            )
+            .add_system_to_stage(
+                CoreStage::PreUpdate,
+                update_cursor_tile
+                    .label(PickingSystem::CursorTile)
+                    .after(PickingSystem::CursorWorld),
+            )
+            .add_system_to_stage(
+                CoreStage::PreUpdate,
+                update_hovered_entity
+                    .label(PickingSystem::HoveredEntity)
+                    .after(PickingSystem::CursorWorld),
+            )
+            .add_system_to_stage(
+                CoreStage::PreUpdate,
+                send_tile_clicks
+                    .label(PickingSystem::TileClicked)
+                    .after(PickingSystem::CursorTile)
+                    .after(ActionSystem),
+            );
+    }
+}
+
+/// The world position shown at `position` in a window of `size`, for a
+/// camera with the given transform and projection scale
+pub fn window_to_world(position: Vec2, size: Vec2, camera: &GlobalTransform, scale: f32) -> Vec2 {
+    // The orthographic projection is in pixels from the window centre times
+    // its scale; the camera transform takes care of translation and rotation
+    let p = (position - size / 2.0) * scale;
+    camera.mul_vec3(p.extend(0.)).truncate()
+}
+
+/// The tile whose diamond contains the world position `p`.
+///
+/// Tile centres sit at whole iso coordinates, so in iso space each diamond is
+/// the unit square around one and rounding follows its edges exactly.
+pub fn world_to_tile(p: Vec2) -> Option<TilePos> {
+    let iso = project_iso(&p).round();
+    if iso.x < 0. || iso.y < 0. {
+        return None;
+    }
+    Some(TilePos(iso.x as u32, iso.y as u32))
+}
+
+/// The world position of the centre of `pos`
+pub fn tile_to_world(pos: TilePos) -> Vec2 {
+    iso_to_world(&Vec2::new(pos.0 as f32, pos.1 as f32))
+}
+
+/// The tile under world position `p` on a map drawn with transform `map`.
+///
+/// `spawn_map` lifts maps by `MAP_LIFT` so their diamonds line up with
+/// `tile_to_world`; only moving a map away from that shifts its tiles.
+pub fn map_world_to_tile(p: Vec2, map: &GlobalTransform) -> Option<TilePos> {
+    let local = map
+        .compute_matrix()
+        .inverse()
+        .transform_point3(p.extend(0.))
+        .truncate();
+    world_to_tile(local + Vec2::new(0., MAP_LIFT))
+}
+
+fn update_cursor_world(
+    windows: Res<Windows>,
+    query: Query<(&GlobalTransform, &OrthographicProjection, &Camera), With<WorldCamera>>,
+    mut cursor_world: ResMut<CursorWorld>,
+) {
+    let current = windows.get_primary().and_then(|win| {
+        let cursor = win.cursor_position()?;
+        let (t, o, _) = query.get_single().ok()?;
+        let size = Vec2::new(win.width(), win.height());
+        Some(window_to_world(cursor, size, t, o.scale))
+    });
+
+    *cursor_world = CursorWorld {
+        current,
+        previous: cursor_world.current,
+    };
+}
+
+/// Finds the hovered tile. The cursor is over no tile while it is over a UI
+/// panel, so clicks on panels don't reach the map.
+fn update_cursor_tile(
+    bounds: Res<MapBounds>,
+    cursor_world: Res<CursorWorld>,
+    maps: Query<(&Map, &GlobalTransform)>,
+    mut egui_context: ResMut<EguiContext>,
+    mut cursor_tile: ResMut<CursorTile>,
+    mut entered: EventWriter<TileHoverEntered>,
+    mut left: EventWriter<TileHoverLeft>,
+) {
+    let over_ui = egui_context.ctx_mut().wants_pointer_input();
+    let map = maps
+        .iter()
+        .find(|(map, _)| map.id == MAP_ID)
+        .map_or_else(|| GlobalTransform::from_xyz(0., MAP_LIFT, 0.), |(_, t)| *t);
+    let current = cursor_world
+        .current
+        .filter(|_| !over_ui)
+        .and_then(|p| map_world_to_tile(p, &map))
+        .filter(|tp| bounds.contains(*tp));
+    let previous = cursor_tile.current;
+    if current == previous {
+        return;
+    }
+
+    if let Some(tp) = previous {
+        left.send(TileHoverLeft(tp));
+    }
+    if let Some(tp) = current {
+        entered.send(TileHoverEntered(tp));
+    }
+    *cursor_tile = CursorTile { current, previous };
+}
+
+fn update_hovered_entity(
+    cursor_world: Res<CursorWorld>,
+    query: Query<(Entity, &GlobalTransform, &Pickable)>,
+    mut hovered: ResMut<HoveredEntity>,
+) {
+    let curr = cursor_world.current.and_then(|p| {
+        query
+            .iter()
+            .filter(|(_, t, pickable)| {
+                let half = pickable.size * t.scale.truncate() / 2.;
+                let local = t.rotation.inverse() * (p.extend(0.) - t.translation);
+                local.x.abs() <= half.x && local.y.abs() <= half.y
+            })
+            .max_by(|(_, a, _), (_, b, _)| {
+                a.translation
+                    .z
+                    .partial_cmp(&b.translation.z)
+                    .unwrap_or(Ordering::Equal)
+            })
+            .map(|(e, _, _)| e)
+    });
+
+    if hovered.0 != curr {
+        hovered.0 = curr;
+    }
+}
+
+fn send_tile_clicks(
+    actions: Res<Input<Action>>,
+    cursor_world: Res<CursorWorld>,
+    cursor_tile: Res<CursorTile>,
+    mut clicks: EventWriter<TileClicked>,
+) {
+    if !actions.just_pressed(Action::Interact) {
+        return;
+    }
+    if let (Some(world), Some(pos)) = (cursor_world.current, cursor_tile.current) {
+        clicks.send(TileClicked {
+            pos,
+            world,
+            select: actions.pressed(Action::Select),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The map transform `spawn_map` uses
+    fn lifted_map() -> GlobalTransform {
+        GlobalTransform::from_xyz(0., MAP_LIFT, 0.)
+    }
+
+    #[test]
+    fn tiles_round_trip_through_world_space() {
+        for x in 0..8 {
+            for y in 0..8 {
+                let tp = TilePos(x, y);
+                let world = tile_to_world(tp);
+                assert_eq!(world_to_tile(world), Some(tp));
+                assert_eq!(map_world_to_tile(world, &lifted_map()), Some(tp));
+
+                let iso = Vec2::new(x as f32, y as f32);
+                assert_eq!(project_iso(&iso_to_world(&iso)), iso);
+            }
+        }
+    }
+
+    #[test]
+    fn diamond_edges_pick_the_right_tile() {
+        let tp = TilePos(4, 4);
+        let centre = tile_to_world(tp);
+        // Corners of the 16x8 diamond, just inside and just outside
+        let inside = [(7.9, 0.), (-7.9, 0.), (0., 3.9), (0., -3.9), (3.9, 1.9)];
+        for (dx, dy) in inside {
+            assert_eq!(
+                world_to_tile(centre + Vec2::new(dx, dy)),
+                Some(tp),
+                "{} {}",
+                dx,
+                dy
+            );
+        }
+        let outside = [
+            ((8.1, 0.), TilePos(5, 3)),
+            ((-8.1, 0.), TilePos(3, 5)),
+            ((0., 4.1), TilePos(3, 3)),
+            ((0., -4.1), TilePos(5, 5)),
+            ((4.1, 2.1), TilePos(4, 3)),
+        ];
+        for ((dx, dy), expected) in outside {
+            assert_eq!(
+                world_to_tile(centre + Vec2::new(dx, dy)),
+                Some(expected),
+                "{} {}",
+                dx,
+                dy
+            );
+        }
+    }
+
+    #[test]
+    fn tiles_left_of_the_map_are_none() {
+        assert_eq!(
+            world_to_tile(tile_to_world(TilePos(0, 0)) + Vec2::new(0., 4.1)),
+            None
+        );
+    }
+
+    #[test]
+    fn picking_follows_a_moved_map() {
+        let moved = GlobalTransform::from_xyz(32., MAP_LIFT, 0.);
+        let tp = TilePos(2, 2);
+        let world = tile_to_world(tp) + Vec2::new(32., 0.);
+        assert_eq!(map_world_to_tile(world, &moved), Some(tp));
+    }
+
+    #[test]
+    fn window_centre_is_the_camera_position() {
+        let size = Vec2::new(800., 600.);
+        let camera = GlobalTransform::from_xyz(100., -50., 999.);
+        assert_eq!(
+            window_to_world(size / 2., size, &camera, 0.25),
+            Vec2::new(100., -50.)
+        );
+        // Window pixels are scaled by the projection
+        assert_eq!(
+            window_to_world(size / 2. + Vec2::new(40., -20.), size, &camera, 0.25),
+            Vec2::new(110., -55.)
+        );
+    }
+}
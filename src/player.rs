@@ -1,209 +1,168 @@
-use std::time::Duration;
-
-use bevy::core::FixedTimestep;
 use bevy::prelude::Plugin as BevyPlugin;
 use bevy::prelude::*;
 use bevy_ecs_tilemap::TilePos;
 
-use crate::camera::CameraFollow;
-use crate::pathfinding::TilePath;
-use crate::sprite::CharacterAnimation;
-use crate::utils::*;
+use crate::camera::CameraTarget;
+use crate::characters::{CharacterSheet, CharacterSheets};
+use crate::depth::DepthSort;
+use crate::input::Action;
+use crate::movement::{MovementSpeed, TileMover};
+use crate::net::NetMode;
+use crate::pathfinding::{Destination, NavGrid, PathTask, PathfindingSettings, TilePath};
+use crate::picking::{tile_to_world, Pickable};
+use crate::sprite::{
+    spawn_character_sprite, Animation, AnimationController, AnimationPlayer, CharacterAppearance,
+    Facing,
+};
+use crate::tile_editor::EditorState;
 
 pub struct Plugin;
 
 #[derive(Component, Debug, Default, Clone, Copy)]
 pub struct PlayerCharacter;
 
-#[derive(Component, Debug, Clone, Copy)]
-pub enum Animation {
-    Idle,
-    Walk,
-    Dance,
-    Jump,
-}
-
-impl Default for Animation {
-    fn default() -> Self {
-        Animation::Idle
-    }
-}
-
-impl Animation {
-    pub fn frames(&self) -> Vec<&'static str> {
-        use Animation::*;
-        match *self {
-            Idle => vec![
-                "characters/basic/basic_idle_01.png",
-                "characters/basic/basic_idle_02.png",
-                "characters/basic/basic_idle_03.png",
-                "characters/basic/basic_idle_04.png",
-            ],
-            Walk => vec![
-                "characters/basic/basic_run_01.png",
-                "characters/basic/basic_run_02.png",
-                "characters/basic/basic_run_03.png",
-                "characters/basic/basic_run_04.png",
-                "characters/basic/basic_run_05.png",
-                "characters/basic/basic_run_06.png",
-                "characters/basic/basic_run_07.png",
-                "characters/basic/basic_run_08.png",
-            ],
-            Dance | Jump => vec![
-                "characters/basic/basic_jump_01.png",
-                "characters/basic/basic_jump_02.png",
-                "characters/basic/basic_jump_03.png",
-                "characters/basic/basic_jump_04.png",
-                "characters/basic/basic_jump_05.png",
-                "characters/basic/basic_jump_06.png",
-                "characters/basic/basic_jump_07.png",
-                "characters/basic/basic_jump_08.png",
-                "characters/basic/basic_jump_09.png",
-                "characters/basic/basic_jump_10.png",
-            ],
-        }
-    }
-    pub fn repeats(&self) -> bool {
-        use Animation::*;
-        match *self {
-            Walk | Idle | Dance => true,
-            Jump => false,
-        }
-    }
-}
-
 impl BevyPlugin for Plugin {
     fn build(&self, app: &mut App) {
-        app.add_system(player_input)
-            .add_system(animate_sprite)
-            .add_stage_after(
-                CoreStage::Update,
-                "player_move",
-                SystemStage::parallel()
-                    .with_run_criteria(FixedTimestep::steps_per_second(10.))
-                    .with_system(path_mover),
-            )
-            .add_system_set(
-                SystemSet::on_enter(crate::tiles::AssetState::Loaded).with_system(setup),
-            );
+        app.add_system_set(
+            SystemSet::on_update(EditorState::Play)
+                .with_system(player_input)
+                .with_system(cancel_input),
+        )
+        .add_system(jump_input)
+        .add_system_set(
+            SystemSet::on_update(crate::tiles::AssetState::Loaded).with_system(spawn_player),
+        );
     }
 }
 
-fn setup(
+/// Name of the character the player is drawn as
+const PLAYER_CHARACTER: &str = "basic";
+
+/// Spawns the player once the character sheet has loaded. Clients show the
+/// server's characters instead.
+fn spawn_player(
     mut commands: Commands,
-    asset_server: Res<AssetServer>,
-    mut texture_atlases: ResMut<Assets<TextureAtlas>>,
-    mut textures: ResMut<Assets<Image>>,
+    net_mode: Option<Res<NetMode>>,
+    sheets: Res<CharacterSheets>,
+    sheet_assets: Res<Assets<CharacterSheet>>,
+    players: Query<(), With<PlayerCharacter>>,
 ) {
-    let texture_handles: Vec<Handle<Image>> = vec![
-        asset_server.load("characters/basic/basic_idle_01.png"),
-        asset_server.load("characters/basic/basic_idle_02.png"),
-        asset_server.load("characters/basic/basic_idle_03.png"),
-        asset_server.load("characters/basic/basic_idle_04.png"),
-    ];
-    let mut tab = TextureAtlasBuilder::default(); //::add_texture(&mut self, texture_handle, texture)//from_grid(texture_handle, Vec2::new(64., 64.), 13, 21);
-    texture_handles.iter().for_each(|t| {
-        tab.add_texture(t.clone(), textures.get(t).expect("character tex setup"));
-    });
-    let texture_atlas_handle =
-        texture_atlases.add(tab.finish(&mut textures).expect("texture_atlas_builder"));
-    let sprite_bundle = SpriteSheetBundle {
-        texture_atlas: texture_atlas_handle,
-        transform: Transform::from_xyz(0., 0., 100.0),
-        ..Default::default()
+    if !players.is_empty() || net_mode.map_or(false, |mode| mode.is_client()) {
+        return;
+    }
+    let sheet = match sheet_assets.get(&sheets.handle) {
+        Some(sheet) => sheet,
+        None => return,
     };
-    commands
-        .spawn_bundle(sprite_bundle)
-        .insert(Transform::from_xyz(0., 0., 100.))
-        .insert(CharacterAnimation(
-            Timer::new(Duration::from_secs_f32(0.25), true),
-            true,
-            4,
-        ))
-        .insert(CameraFollow)
-        .insert(PlayerCharacter::default());
-}
-
-fn player_input(keyboard_input: Res<Input<KeyCode>>, mut query: Query<&mut PlayerCharacter>) {
-    if let Some(mut pc) = query.get_single_mut().ok() {
-        let mut dir = Vec3::ZERO;
-
-        if keyboard_input.pressed(KeyCode::A) {
-            dir.x = -1.0;
-        }
-
-        if keyboard_input.pressed(KeyCode::D) {
-            dir.x = 1.0;
-        }
 
-        if keyboard_input.pressed(KeyCode::W) {
-            dir.y = 1.0;
-        }
-
-        if keyboard_input.pressed(KeyCode::X) {
-            dir.y = -1.0;
-        }
-
-        if keyboard_input.pressed(KeyCode::E) {
-            dir.x = 1.0;
-            dir.y = 1.0;
-        }
-
-        if keyboard_input.pressed(KeyCode::Q) {
-            dir.x = -1.0;
-            dir.y = 1.0;
-        }
-
-        if keyboard_input.pressed(KeyCode::Z) {
-            dir.x = -1.0;
-            dir.y = -1.0;
-        }
+    let tile = TilePos(0, 0);
+    let player = commands
+        .spawn()
+        .insert(Transform::from_translation(tile_to_world(tile).extend(0.)))
+        .insert(DepthSort::default())
+        .insert(GlobalTransform::default())
+        .insert(CharacterAppearance::new(PLAYER_CHARACTER))
+        .insert(AnimationPlayer::default())
+        .insert(AnimationController::default())
+        .insert(Facing::default())
+        .insert(MovementSpeed::default())
+        .insert(TileMover::new(tile))
+        .insert(CameraTarget)
+        .insert(Pickable::new(Vec2::new(16., 16.)))
+        .insert(PlayerCharacter::default())
+        .id();
+    spawn_character_sprite(&mut commands, player, sheet);
+}
 
-        if keyboard_input.pressed(KeyCode::C) {
-            dir.x = 1.0;
-            dir.y = -1.0;
+/// Steps the player tile by tile while movement actions are held, using the
+/// same walkability rules as pathfinding
+fn player_input(
+    actions: Res<Input<Action>>,
+    grid: Res<NavGrid>,
+    settings: Res<PathfindingSettings>,
+    mut commands: Commands,
+    mut moving: Local<bool>,
+    query: Query<(Entity, &TileMover, Option<&TilePath>), With<PlayerCharacter>>,
+) {
+    // Holding two directions combines them
+    let (sx, sy) = Action::MOVES
+        .iter()
+        .filter(|(action, _)| actions.pressed(*action))
+        .fold((0, 0), |(x, y), (_, facing)| {
+            let (dx, dy) = facing.screen();
+            (x + dx, y + dy)
+        });
+    let facing = match Facing::from_screen(sx, sy) {
+        Some(facing) => facing,
+        None => {
+            // Stop after the current step once the keys are released
+            if *moving {
+                *moving = false;
+                for (e, _, _) in query.iter() {
+                    commands.entity(e).remove::<TilePath>();
+                }
+            }
+            return;
         }
-
-        if dir != Vec3::ZERO {
-            dir.x *= 16.0;
-            dir.y *= 8.0;
-
-            if dir.x != 0.0 && dir.y != 0.0 {
-                dir *= 0.5;
+    };
+    *moving = true;
+
+    for (e, mover, path) in query.iter() {
+        // Keyboard movement takes over from any click-to-move path
+        commands
+            .entity(e)
+            .remove::<Destination>()
+            .remove::<PathTask>();
+
+        // Queue the step after the current one so held keys move smoothly
+        let tile = mover.next_tile();
+        let (dx, dy) = facing.step();
+        let target = grid
+            .neighbors(tile, settings.movement)
+            .map(|(pos, _)| pos)
+            .find(|pos| pos.0 as i64 - tile.0 as i64 == dx && pos.1 as i64 - tile.1 as i64 == dy);
+
+        match target {
+            Some(target) => {
+                if path.map(|p| p.0.as_slice()) != Some(&[target][..]) {
+                    commands.entity(e).insert(TilePath(vec![target]));
+                }
+            }
+            None => {
+                commands.entity(e).remove::<TilePath>();
+                // Still turn to face a blocked direction
+                if !mover.is_stepping() {
+                    commands.entity(e).insert(facing);
+                }
             }
         }
     }
 }
 
-fn path_mover(mut query: Query<(Entity, &mut Transform, &TilePath)>, mut commands: Commands) {
-    for (e, mut t, path) in query.iter_mut() {
-        let mut updated_path = path.0.clone();
-        let opt_next = updated_path.pop();
-        if let Some(pos) = opt_next {
-            let wpos = iso_to_world(&Vec2::new(pos.0 as f32, pos.1 as f32));
-            t.translation = wpos.extend(100.);
-            commands.entity(e).insert(TilePath(updated_path));
-        } else {
-            commands.entity(e).remove::<TilePath>();
+fn jump_input(
+    actions: Res<Input<Action>>,
+    mut query: Query<&mut AnimationController, With<PlayerCharacter>>,
+) {
+    if actions.just_pressed(Action::Jump) {
+        for mut controller in query.iter_mut() {
+            controller.play_once(Animation::Jump);
         }
     }
 }
 
-fn animate_sprite(
-    time: Res<Time>,
-    mut query: Query<(
-        &mut CharacterAnimation,
-        &mut TextureAtlasSprite,
-        &Handle<TextureAtlas>,
-    )>,
-    texture_atlases: Res<Assets<TextureAtlas>>,
+/// `Action::Cancel` stops the player after the current step
+fn cancel_input(
+    actions: Res<Input<Action>>,
+    mut commands: Commands,
+    query: Query<Entity, With<PlayerCharacter>>,
 ) {
-    for (mut timer, mut sprite, ta) in query.iter_mut() {
-        timer.0.tick(time.delta());
-        if timer.1 && timer.0.just_finished() {
-            let taa = texture_atlases.get(ta).expect("texture atlas for anim");
-            sprite.index = (sprite.index + 1) % taa.textures.len();
+    if actions.just_pressed(Action::Cancel) {
+        for e in query.iter() {
+            commands
+                .entity(e)
+                .remove::<Destination>()
+                .remove::<PathTask>()
+                .remove::<TilePath>();
         }
     }
 }
-//! https://github.com/lucaspoffo/renet/tree/master/bevy_renet
\ No newline at end of file
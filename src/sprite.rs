@@ -1,5 +1,600 @@
-// use bevy::prelude::Plugin as BevyPlugin;
+use std::time::Duration;
+
+use bevy::prelude::Plugin as BevyPlugin;
 use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::characters::{AnimationClip, CharacterSheet, CharacterSheets};
+use crate::movement::TileMover;
+use crate::pathfinding::TilePath;
+
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Animation {
+    Idle,
+    Walk,
+    Dance,
+    Jump,
+    Attack,
+}
+
+impl Default for Animation {
+    fn default() -> Self {
+        Animation::Idle
+    }
+}
+
+impl Animation {
+    /// Name of this animation's clip in the character sheet
+    pub fn clip(&self) -> &'static str {
+        use Animation::*;
+        match *self {
+            Idle => "idle",
+            Walk => "run",
+            Dance | Jump => "jump",
+            Attack => "attack",
+        }
+    }
+    pub fn repeats(&self) -> bool {
+        use Animation::*;
+        match *self {
+            Walk | Idle | Dance => true,
+            Jump | Attack => false,
+        }
+    }
+}
+
+/// Picks a character's animation: Walk while it follows a `TilePath`, Idle
+/// otherwise, with one-shots played over either and then dropped
+#[derive(Debug, Component, Clone, Copy, Default)]
+pub struct AnimationController {
+    /// The looping animation chosen from movement
+    pub base: Animation,
+    /// An animation played once over `base`
+    pub one_shot: Option<Animation>,
+    /// Counts `play_once` calls, so playing a one-shot again restarts it
+    pub generation: u32,
+}
+
+impl AnimationController {
+    /// Plays `animation` once, then falls back to the movement animation
+    pub fn play_once(&mut self, animation: Animation) {
+        self.one_shot = Some(animation);
+        self.generation = self.generation.wrapping_add(1);
+    }
+
+    pub fn current(&self) -> Animation {
+        self.one_shot.unwrap_or(self.base)
+    }
+}
+
+/// The screen direction a character is facing. Art faces right, so the
+/// directions on the left are drawn mirrored.
+#[derive(Debug, Component, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Facing {
+    North,
+    NorthEast,
+    East,
+    SouthEast,
+    South,
+    SouthWest,
+    West,
+    NorthWest,
+}
+
+impl Default for Facing {
+    fn default() -> Self {
+        Facing::SouthEast
+    }
+}
+
+impl Facing {
+    /// The direction of a step of `dx`, `dy` tiles, or `None` for no step.
+    /// See `utils::iso_to_world` for how tile axes map to the screen.
+    pub fn from_step(dx: i64, dy: i64) -> Option<Self> {
+        Facing::from_screen(dx - dy, -(dx + dy))
+    }
+
+    /// The direction of a screen vector, y-up, or `None` for zero
+    pub fn from_screen(x: i64, y: i64) -> Option<Self> {
+        use Facing::*;
+        Some(match (x.signum(), y.signum()) {
+            (0, 1) => North,
+            (1, 1) => NorthEast,
+            (1, 0) => East,
+            (1, -1) => SouthEast,
+            (0, -1) => South,
+            (-1, -1) => SouthWest,
+            (-1, 0) => West,
+            (-1, 1) => NorthWest,
+            _ => return None,
+        })
+    }
+
+    /// The one-tile step that moves this way on screen
+    pub fn step(self) -> (i64, i64) {
+        use Facing::*;
+        match self {
+            North => (-1, -1),
+            NorthEast => (0, -1),
+            East => (1, -1),
+            SouthEast => (1, 0),
+            South => (1, 1),
+            SouthWest => (0, 1),
+            West => (-1, 1),
+            NorthWest => (-1, 0),
+        }
+    }
+
+    /// Unit screen direction, y-up
+    pub fn screen(self) -> (i64, i64) {
+        let (dx, dy) = self.step();
+        ((dx - dy).signum(), -(dx + dy).signum())
+    }
 
+    /// Suffix of per-direction clips, e.g. "run_ne"
+    pub fn suffix(self) -> &'static str {
+        use Facing::*;
+        match self {
+            North => "n",
+            NorthEast => "ne",
+            East => "e",
+            SouthEast => "se",
+            South => "s",
+            SouthWest => "sw",
+            West => "w",
+            NorthWest => "nw",
+        }
+    }
+
+    /// The direction mirrored left to right
+    pub fn mirrored(self) -> Self {
+        use Facing::*;
+        match self {
+            NorthEast => NorthWest,
+            East => West,
+            SouthEast => SouthWest,
+            SouthWest => SouthEast,
+            West => East,
+            NorthWest => NorthEast,
+            North | South => self,
+        }
+    }
+
+    /// Whether the right-facing art is flipped for this direction. Straight
+    /// up and down aren't flipped.
+    pub fn flip_x(self) -> bool {
+        matches!(self, Facing::SouthWest | Facing::West | Facing::NorthWest)
+    }
+}
+
+/// The clip to draw for `clip` facing `facing`, and whether to flip it.
+/// Prefers art drawn for the direction, then mirrored art, then the plain
+/// clip.
+fn directional_clip<'a>(
+    sheet: &'a CharacterSheet,
+    character: &str,
+    clip: &str,
+    facing: Option<Facing>,
+) -> Option<(&'a AnimationClip, bool)> {
+    if let Some(facing) = facing {
+        let exact = format!("{}_{}", clip, facing.suffix());
+        if let Some(c) = sheet.clip(character, &exact) {
+            return Some((c, false));
+        }
+        let mirrored = format!("{}_{}", clip, facing.mirrored().suffix());
+        if let Some(c) = sheet.clip(character, &mirrored) {
+            return Some((c, true));
+        }
+    }
+    let flip = facing.map_or(false, Facing::flip_x);
+    sheet.clip(character, clip).map(|c| (c, flip))
+}
+
+/// A clip that doesn't repeat played its last frame
+#[derive(Debug, Clone)]
+pub struct AnimationFinished {
+    pub entity: Entity,
+    pub clip: String,
+    /// The `AnimationPlayer::generation` that finished
+    pub generation: u32,
+}
+
+/// A clip moved on to a new frame
+#[derive(Debug, Clone)]
+pub struct AnimationFrame {
+    pub entity: Entity,
+    pub clip: String,
+    pub frame: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, SystemLabel)]
+pub enum AnimationSystem {
+    Controller,
+    Player,
+}
+
+/// Which character from the sheet an entity is drawn as
 #[derive(Debug, Component, Clone)]
-pub struct CharacterAnimation(pub Timer, pub bool, pub usize);
+pub struct CharacterAppearance {
+    pub name: String,
+}
+
+impl CharacterAppearance {
+    pub fn new(name: impl Into<String>) -> Self {
+        CharacterAppearance { name: name.into() }
+    }
+}
+
+/// Plays a clip of the entity's `CharacterAppearance` on its
+/// `CharacterSprite` child
+#[derive(Debug, Component, Clone, Default)]
+pub struct AnimationPlayer {
+    pub clip: String,
+    pub repeat: bool,
+    pub frame: usize,
+    pub elapsed: Duration,
+    pub finished: bool,
+    /// The `AnimationController::generation` this clip was started for
+    pub generation: u32,
+}
+
+impl AnimationPlayer {
+    pub fn new(clip: impl Into<String>, repeat: bool) -> Self {
+        AnimationPlayer {
+            clip: clip.into(),
+            repeat,
+            ..Default::default()
+        }
+    }
+
+    /// Starts `clip` from its first frame
+    pub fn play(&mut self, clip: impl Into<String>, repeat: bool) {
+        *self = AnimationPlayer::new(clip, repeat);
+    }
+}
+
+/// The child entity that draws a character. It is separate from the
+/// character so frame offsets don't move the character itself.
+#[derive(Debug, Component, Clone, Copy, Default)]
+pub struct CharacterSprite;
+
+pub struct Plugin;
+
+impl BevyPlugin for Plugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<AnimationFinished>()
+            .add_event::<AnimationFrame>()
+            .add_system_set(
+                SystemSet::new()
+                    .label(AnimationSystem::Controller)
+                    .with_system(movement_animation)
+                    .with_system(end_one_shots),
+            )
+            .add_system(
+                play_animations
+                    .label(AnimationSystem::Player)
+                    .after(AnimationSystem::Controller),
+            );
+    }
+}
+
+/// Spawns the sprite child for a character entity
+pub fn spawn_character_sprite(
+    commands: &mut Commands,
+    parent: Entity,
+    sheet: &CharacterSheet,
+) -> Entity {
+    let sprite = commands
+        .spawn_bundle(SpriteSheetBundle {
+            texture_atlas: sheet.atlas.clone(),
+            ..Default::default()
+        })
+        .insert(CharacterSprite)
+        .id();
+    commands.entity(parent).push_children(&[sprite]);
+    sprite
+}
+
+/// Switches between Walk and Idle as characters moved on this machine start
+/// and stop moving
+fn movement_animation(mut query: Query<(&mut AnimationController, Option<&TilePath>, &TileMover)>) {
+    for (mut controller, path, mover) in query.iter_mut() {
+        let moving = path.is_some() || mover.is_stepping();
+        let base = if moving {
+            Animation::Walk
+        } else {
+            Animation::Idle
+        };
+        if controller.base != base {
+            controller.base = base;
+        }
+    }
+}
+
+/// Drops finished one-shots and keeps each `AnimationPlayer` on its
+/// controller's current clip
+fn end_one_shots(
+    mut finished: EventReader<AnimationFinished>,
+    mut query: Query<(&mut AnimationController, &mut AnimationPlayer)>,
+) {
+    // A one-shot played again since this one finished keeps going
+    for event in finished.iter() {
+        if let Ok((mut controller, _)) = query.get_mut(event.entity) {
+            let clip = controller.one_shot.map(|a| a.clip());
+            if clip == Some(event.clip.as_str()) && controller.generation == event.generation {
+                controller.one_shot = None;
+            }
+        }
+    }
+
+    for (controller, mut player) in query.iter_mut() {
+        let animation = controller.current();
+        if player.clip != animation.clip() || player.generation != controller.generation {
+            let repeat = controller.one_shot.is_none() && animation.repeats();
+            player.play(animation.clip(), repeat);
+            player.generation = controller.generation;
+        }
+    }
+}
+
+fn play_animations(
+    time: Res<Time>,
+    sheets: Res<CharacterSheets>,
+    sheet_assets: Res<Assets<CharacterSheet>>,
+    mut finished: EventWriter<AnimationFinished>,
+    mut frames: EventWriter<AnimationFrame>,
+    mut characters: Query<(
+        Entity,
+        &CharacterAppearance,
+        &mut AnimationPlayer,
+        Option<&Facing>,
+        &Children,
+    )>,
+    mut sprites: Query<(&mut TextureAtlasSprite, &mut Transform), With<CharacterSprite>>,
+) {
+    let sheet = match sheet_assets.get(&sheets.handle) {
+        Some(sheet) => sheet,
+        None => return,
+    };
+
+    for (entity, appearance, mut player, facing, children) in characters.iter_mut() {
+        let facing = facing.copied();
+        let (clip, flip_x) = match directional_clip(sheet, &appearance.name, &player.clip, facing) {
+            Some((clip, flip_x)) if !clip.frames.is_empty() => (clip, flip_x),
+            // A one-shot this character has no art for ends straight away
+            _ => {
+                if !player.repeat && !player.finished {
+                    player.finished = true;
+                    finished.send(AnimationFinished {
+                        entity,
+                        clip: player.clip.clone(),
+                        generation: player.generation,
+                    });
+                }
+                continue;
+            }
+        };
+        let start_frame = player.frame;
+
+        // Zero-length clips would never finish a frame
+        if !player.finished && clip.duration() > Duration::ZERO {
+            player.elapsed += time.delta();
+            while player.elapsed >= clip.frames[player.frame % clip.frames.len()].duration {
+                player.elapsed -= clip.frames[player.frame % clip.frames.len()].duration;
+                if player.frame + 1 < clip.frames.len() {
+                    player.frame += 1;
+                } else if player.repeat {
+                    player.frame = 0;
+                } else {
+                    player.finished = true;
+                    player.elapsed = Duration::ZERO;
+                    finished.send(AnimationFinished {
+                        entity,
+                        clip: player.clip.clone(),
+                        generation: player.generation,
+                    });
+                    break;
+                }
+            }
+        }
+
+        if player.frame != start_frame {
+            frames.send(AnimationFrame {
+                entity,
+                clip: player.clip.clone(),
+                frame: player.frame,
+            });
+        }
+
+        let frame = clip.frames[player.frame.min(clip.frames.len() - 1)];
+        for child in children.iter() {
+            if let Ok((mut sprite, mut t)) = sprites.get_mut(*child) {
+                sprite.index = frame.index;
+                sprite.flip_x = flip_x;
+                t.translation.x = if flip_x {
+                    -frame.offset.x
+                } else {
+                    frame.offset.x
+                };
+                t.translation.y = frame.offset.y;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::asset::AssetPlugin;
+    use bevy::ecs::event::Events;
+    use bevy::tasks::{IoTaskPool, TaskPool};
+    use bevy::utils::HashMap;
+    use bevy_ecs_tilemap::TilePos;
+
+    use super::*;
+    use crate::characters::{Character, ClipFrame};
+
+    const FRAME: Duration = Duration::from_millis(1);
+
+    fn clip(first: usize, frames: usize) -> AnimationClip {
+        AnimationClip {
+            frames: (first..first + frames)
+                .map(|index| ClipFrame {
+                    index,
+                    duration: FRAME,
+                    offset: Vec2::ZERO,
+                })
+                .collect(),
+        }
+    }
+
+    /// An app running the animation systems over a sheet where "basic" can
+    /// idle, run and jump
+    fn animation_app() -> App {
+        let mut character = Character::default();
+        character.clips.insert("idle".to_string(), clip(0, 2));
+        character.clips.insert("run".to_string(), clip(2, 2));
+        character.clips.insert("jump".to_string(), clip(4, 3));
+        let mut characters = HashMap::default();
+        characters.insert("basic".to_string(), character);
+
+        let mut app = App::new();
+        app.insert_resource(IoTaskPool(TaskPool::new()))
+            .add_plugin(AssetPlugin)
+            .add_asset::<CharacterSheet>()
+            .init_resource::<Time>()
+            .add_plugin(Plugin);
+        let handle = app
+            .world
+            .get_resource_mut::<Assets<CharacterSheet>>()
+            .unwrap()
+            .add(CharacterSheet {
+                atlas: Handle::default(),
+                characters,
+            });
+        app.insert_resource(CharacterSheets { handle });
+        app
+    }
+
+    fn spawn_character(app: &mut App) -> Entity {
+        let sprite = app
+            .world
+            .spawn()
+            .insert(TextureAtlasSprite::default())
+            .insert(Transform::default())
+            .insert(CharacterSprite)
+            .id();
+        app.world
+            .spawn()
+            .insert(CharacterAppearance::new("basic"))
+            .insert(AnimationPlayer::default())
+            .insert(AnimationController::default())
+            .insert(TileMover::new(TilePos(0, 0)))
+            .push_children(&[sprite])
+            .id()
+    }
+
+    /// Runs a frame at least `FRAME` after the last one
+    fn tick(app: &mut App) {
+        std::thread::sleep(FRAME);
+        app.world.get_resource_mut::<Time>().unwrap().update();
+        app.update();
+    }
+
+    fn player(app: &App, e: Entity) -> &AnimationPlayer {
+        app.world.get::<AnimationPlayer>(e).unwrap()
+    }
+
+    fn controller(app: &mut App, e: Entity) -> Mut<AnimationController> {
+        app.world.get_mut::<AnimationController>(e).unwrap()
+    }
+
+    #[test]
+    fn characters_walk_while_following_a_path() {
+        let mut app = animation_app();
+        let e = spawn_character(&mut app);
+        tick(&mut app);
+        assert_eq!(player(&app, e).clip, "idle");
+
+        app.world
+            .entity_mut(e)
+            .insert(TilePath(vec![TilePos(1, 0)]));
+        tick(&mut app);
+        assert_eq!(controller(&mut app, e).base, Animation::Walk);
+        assert_eq!(player(&app, e).clip, "run");
+        assert!(player(&app, e).repeat);
+
+        app.world.entity_mut(e).remove::<TilePath>();
+        tick(&mut app);
+        assert_eq!(controller(&mut app, e).base, Animation::Idle);
+        assert_eq!(player(&app, e).clip, "idle");
+    }
+
+    #[test]
+    fn one_shots_fall_back_to_the_base_clip() {
+        let mut app = animation_app();
+        let e = spawn_character(&mut app);
+        tick(&mut app);
+
+        controller(&mut app, e).play_once(Animation::Jump);
+        let mut finished_reader = app
+            .world
+            .get_resource::<Events<AnimationFinished>>()
+            .unwrap()
+            .get_reader();
+        let mut frame_reader = app
+            .world
+            .get_resource::<Events<AnimationFrame>>()
+            .unwrap()
+            .get_reader();
+        let (mut finished, mut frames) = (Vec::new(), Vec::new());
+        for _ in 0..1000 {
+            tick(&mut app);
+            let events = app
+                .world
+                .get_resource::<Events<AnimationFinished>>()
+                .unwrap();
+            finished.extend(
+                finished_reader
+                    .iter(events)
+                    .map(|f| (f.entity, f.clip.clone())),
+            );
+            let events = app.world.get_resource::<Events<AnimationFrame>>().unwrap();
+            frames.extend(
+                frame_reader
+                    .iter(events)
+                    .map(|f| (f.entity, f.clip.clone())),
+            );
+            if controller(&mut app, e).one_shot.is_none() {
+                break;
+            }
+        }
+
+        assert_eq!(finished, [(e, "jump".to_string())]);
+        assert!(frames.contains(&(e, "jump".to_string())));
+        assert_eq!(controller(&mut app, e).current(), Animation::Idle);
+        assert_eq!(player(&app, e).clip, "idle");
+        assert!(player(&app, e).repeat);
+    }
+
+    #[test]
+    fn replaying_a_finished_one_shot_restarts_it() {
+        let mut app = animation_app();
+        let e = spawn_character(&mut app);
+        controller(&mut app, e).play_once(Animation::Jump);
+        for _ in 0..1000 {
+            tick(&mut app);
+            if player(&app, e).finished {
+                break;
+            }
+        }
+        assert!(player(&app, e).finished);
+
+        // Played again before the finished jump is dropped
+        controller(&mut app, e).play_once(Animation::Jump);
+        tick(&mut app);
+        assert_eq!(controller(&mut app, e).one_shot, Some(Animation::Jump));
+        assert_eq!(player(&app, e).clip, "jump");
+        let generation = controller(&mut app, e).generation;
+        assert_eq!(player(&app, e).generation, generation);
+    }
+}
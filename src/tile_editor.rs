@@ -1,21 +1,19 @@
+use bevy::prelude::Plugin as BevyPlugin;
 use bevy::prelude::*;
-use bevy::{math::Vec3Swizzles, prelude::Plugin as BevyPlugin};
+use bevy::utils::{HashMap, HashSet};
 
+use crate::history::MapEditor;
+use crate::input::Action;
+use crate::movement::TileMover;
+use crate::net::NetMode;
 use crate::pathfinding::TilePath;
-use crate::utils::*;
-use crate::{
-    camera::{WorldCamera, SCALE},
-    pathfinding::Destination,
-    player::PlayerCharacter,
-};
-use bevy::math::Vec4Swizzles;
+use crate::picking::CursorTile;
+use crate::tiles::{MapBounds, MapLayer};
+use crate::tmx::TmxStartup;
+use crate::{pathfinding::Destination, player::PlayerCharacter};
 use bevy_ecs_tilemap::{MapQuery, TilePos, TilemapPlugin};
 use bevy_tileset_map::prelude::*;
 
-/// The position of the cursor at the time of this event
-/// and whether it is pressed or not
-pub struct ClickEvent(pub Vec2, pub bool);
-
 pub struct Plugin;
 
 impl BevyPlugin for Plugin {
@@ -23,12 +21,58 @@ impl BevyPlugin for Plugin {
         app.add_plugin(TilemapPlugin)
             .add_plugin(TilesetPlugin::default())
             .add_plugin(TilesetMapPlugin)
-            .add_event::<ClickEvent>()
             .init_resource::<TerrainTileset>()
             .add_startup_system(load_tiles)
+            .add_state(EditorState::Play)
+            .init_resource::<BrushSettings>()
             .add_system(build_map)
-            .add_system(on_click)
-            .add_system(on_tile_click);
+            .add_system(toggle_editor)
+            .add_system_set(SystemSet::on_update(EditorState::Play).with_system(on_tile_click))
+            .add_system_set(
+                SystemSet::on_update(EditorState::Edit)
+                    .with_system(brush_hotkeys)
+                    .with_system(paint_tiles)
+                    .with_system(clear_layer),
+            );
+    }
+}
+
+/// Whether clicks move the player or edit the map
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EditorState {
+    Play,
+    Edit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Brush {
+    /// Paints every tile the cursor is dragged over
+    Single,
+    /// Fills the rectangle between press and release
+    Rectangle,
+    /// Paints a line between press and release
+    Line,
+    /// Fills the connected area of matching tiles under the cursor
+    Fill,
+}
+
+pub struct BrushSettings {
+    pub brush: Brush,
+    /// Name of the tileset `tile` belongs to
+    pub tileset: String,
+    /// Tile group to paint, or `None` to erase
+    pub tile: Option<String>,
+    pub layer: MapLayer,
+}
+
+impl Default for BrushSettings {
+    fn default() -> Self {
+        BrushSettings {
+            brush: Brush::Single,
+            tileset: "terrain".to_string(),
+            tile: Some("grass".to_string()),
+            layer: MapLayer::Ground,
+        }
     }
 }
 
@@ -49,15 +93,16 @@ struct BuildMapState {
     built: bool,
 }
 
-/// A system used to build the tilemap
+/// A system used to build the tilemap. A `TmxStartup` map is shown instead.
 fn build_map(
     tilesets: Tilesets,
     mut commands: Commands,
     mut map_query: MapQuery,
     mut local_state: Local<BuildMapState>,
     my_tileset: Res<TerrainTileset>,
+    tmx_startup: Option<Res<TmxStartup>>,
 ) {
-    if local_state.built {
+    if local_state.built || tmx_startup.is_some() {
         return;
     }
 
@@ -67,66 +112,349 @@ fn build_map(
     }
 }
 
-pub fn on_click(
-    query: Query<&Transform, With<WorldCamera>>,
-    wnds: Res<Windows>,
-    buttons: Res<Input<MouseButton>>,
-    mut event_writer: EventWriter<ClickEvent>,
+/// A system that sends the player to the hovered tile on `Action::Interact`,
+/// unless `Action::Select` is held
+fn on_tile_click(
+    actions: Res<Input<Action>>,
+    cursor_tile: Res<CursorTile>,
+    mut query: Query<(Entity, &TileMover), With<PlayerCharacter>>,
+    mut commands: Commands,
 ) {
-    let just_pressed = buttons.just_pressed(MouseButton::Left);
-    let just_released = buttons.just_released(MouseButton::Left);
+    if !actions.just_pressed(Action::Interact) || actions.pressed(Action::Select) {
+        return;
+    }
 
-    if !just_pressed && !just_released {
+    if let (Some(goal), Some((e, mover))) = (cursor_tile.current, query.get_single_mut().ok()) {
+        // A step in progress is finished before the new path starts
+        commands
+            .entity(e)
+            .remove::<TilePath>()
+            .insert(Destination::new(mover.next_tile(), goal));
+    }
+}
+
+/// `Action::ToggleEditor` switches between playing and editing, except on
+/// clients
+fn toggle_editor(
+    actions: Res<Input<Action>>,
+    net_mode: Option<Res<NetMode>>,
+    mut state: ResMut<State<EditorState>>,
+) {
+    let can_edit = net_mode.map_or(true, |mode| mode.can_edit());
+    if !actions.just_pressed(Action::ToggleEditor) || !can_edit {
         return;
     }
 
-    let wnd = wnds.get_primary().unwrap();
-    if let Some(pos) = wnd.cursor_position() {
-        let cam = query.single();
-        let p = window_to_world(&pos, wnd, cam);
+    let next = match state.current() {
+        EditorState::Play => EditorState::Edit,
+        EditorState::Edit => EditorState::Play,
+    };
+    state.set(next).ok();
+}
 
-        let ppos = project_iso(&p);
-        if ppos.x < 0. || ppos.y < 0. {
-            return;
+/// The brush actions pick a brush, `Action::ToggleEraser` toggles the eraser
+fn brush_hotkeys(actions: Res<Input<Action>>, mut settings: ResMut<BrushSettings>) {
+    for (action, brush) in [
+        (Action::BrushSingle, Brush::Single),
+        (Action::BrushRectangle, Brush::Rectangle),
+        (Action::BrushLine, Brush::Line),
+        (Action::BrushFill, Brush::Fill),
+    ] {
+        if actions.just_pressed(action) {
+            settings.brush = brush;
         }
-        event_writer.send(ClickEvent(p, just_pressed));
+    }
+
+    if actions.just_pressed(Action::ToggleEraser) {
+        settings.tile = match settings.tile {
+            Some(_) => None,
+            None => BrushSettings::default().tile,
+        };
     }
 }
 
-pub fn window_to_world(position: &Vec2, window: &Window, camera: &Transform) -> Vec2 {
-    // get the size of the window
-    let size = Vec2::new(window.width() as f32, window.height() as f32);
+/// The tile a stroke started on and the last tile it painted
+#[derive(Default)]
+pub struct StrokeState {
+    start: Option<TilePos>,
+    last: Option<TilePos>,
+}
+
+/// Applies the active brush from clicks and drags. Each stroke is one step
+/// in the `EditHistory`.
+fn paint_tiles(
+    actions: Res<Input<Action>>,
+    cursor_tile: Res<CursorTile>,
+    settings: Res<BrushSettings>,
+    bounds: Res<MapBounds>,
+    mut stroke: Local<StrokeState>,
+    mut editor: MapEditor,
+) {
+    let curr = cursor_tile.current;
+    let tile_id = match settings.tile.as_deref() {
+        Some(group) => match editor.tile_id(&settings.tileset, group) {
+            Some(id) => Some(id),
+            None => return,
+        },
+        None => None,
+    };
+
+    let mut positions = Vec::new();
+
+    // Strokes don't start while selecting
+    let starting = actions.just_pressed(Action::EditorPaint) && !actions.pressed(Action::Select);
+    if let (true, Some(tp)) = (starting, curr) {
+        stroke.start = Some(tp);
+        stroke.last = Some(tp);
+        editor.begin_stroke();
+        match settings.brush {
+            Brush::Single => positions.push(tp),
+            Brush::Fill => {
+                let layer_tiles = editor.layer_tiles(&settings.tileset, settings.layer);
+                let size = (bounds.width, bounds.height);
+                positions.extend(flood_fill(tp, size, &layer_tiles));
+            }
+            Brush::Rectangle | Brush::Line => {}
+        }
+    }
+
+    let released = !actions.pressed(Action::EditorPaint);
 
-    // the default orthographic projection is in pixels from the center;
-    // just undo the translation and apply camera scale
-    let p = (*position - size / 2.0) * SCALE;
+    if let Some(start) = stroke.start {
+        match settings.brush {
+            // Dragging a single-tile brush paints a line so fast moves leave no gaps
+            Brush::Single => {
+                if let (Some(last), Some(tp)) = (stroke.last, curr) {
+                    if last != tp {
+                        positions.extend(line(last, tp).into_iter().skip(1));
+                        stroke.last = Some(tp);
+                    }
+                }
+            }
+            // Shapes are painted on release, ending where the cursor last was
+            // on the map
+            Brush::Rectangle | Brush::Line if released => {
+                let end = curr.or(stroke.last).unwrap_or(start);
+                match settings.brush {
+                    Brush::Rectangle => positions.extend(rectangle(start, end)),
+                    _ => positions.extend(line(start, end)),
+                }
+            }
+            Brush::Rectangle | Brush::Line => {
+                if curr.is_some() {
+                    stroke.last = curr;
+                }
+            }
+            Brush::Fill => {}
+        }
+    }
 
-    // apply the camera transform
-    (camera.compute_matrix() * p.extend(0.0).extend(1.0)).xy()
+    positions.retain(|tp| bounds.contains(*tp));
+    editor.paint(&settings.tileset, settings.layer, &positions, tile_id);
+
+    if released {
+        *stroke = StrokeState::default();
+        editor.end_stroke();
+    }
 }
 
-/// A system that adds/removes tiles when clicked
-fn on_tile_click(
-    mut event_reader: EventReader<ClickEvent>,
-    mut query: Query<(Entity, &Transform), With<PlayerCharacter>>,
-    mut commands: Commands,
-) {
-    for ClickEvent(ref p, pressed) in event_reader.iter() {
-        if !pressed {
+/// `Action::ClearLayer` clears the active layer
+fn clear_layer(actions: Res<Input<Action>>, settings: Res<BrushSettings>, mut editor: MapEditor) {
+    if actions.just_pressed(Action::ClearLayer) {
+        editor.clear_layer(&settings.tileset, settings.layer);
+    }
+}
+
+/// Every tile in the rectangle with corners `a` and `b`
+pub fn rectangle(a: TilePos, b: TilePos) -> Vec<TilePos> {
+    let mut v = Vec::new();
+    for x in a.0.min(b.0)..=a.0.max(b.0) {
+        for y in a.1.min(b.1)..=a.1.max(b.1) {
+            v.push(TilePos(x, y));
+        }
+    }
+    v
+}
+
+/// Every tile on the line from `a` to `b`, starting at `a`
+pub fn line(a: TilePos, b: TilePos) -> Vec<TilePos> {
+    // Bresenham
+    let (mut x, mut y) = (a.0 as i64, a.1 as i64);
+    let (x1, y1) = (b.0 as i64, b.1 as i64);
+    let dx = (x1 - x).abs();
+    let dy = -(y1 - y).abs();
+    let sx = if x < x1 { 1 } else { -1 };
+    let sy = if y < y1 { 1 } else { -1 };
+    let mut err = dx + dy;
+
+    let mut v = vec![TilePos(x as u32, y as u32)];
+    while x != x1 || y != y1 {
+        let e2 = 2 * err;
+        if e2 >= dy {
+            err += dy;
+            x += sx;
+        }
+        if e2 <= dx {
+            err += dx;
+            y += sy;
+        }
+        v.push(TilePos(x as u32, y as u32));
+    }
+    v
+}
+
+/// The tiles connected to `start` that hold the same tile, or are empty
+/// like it, within a map of `size`
+pub fn flood_fill<T: PartialEq>(
+    start: TilePos,
+    size: (u32, u32),
+    tiles: &HashMap<TilePos, T>,
+) -> Vec<TilePos> {
+    let target = tiles.get(&start);
+    let mut seen = HashSet::default();
+    let mut stack = vec![start];
+    let mut v = Vec::new();
+
+    while let Some(tp) = stack.pop() {
+        if tp.0 >= size.0 || tp.1 >= size.1 || tiles.get(&tp) != target || !seen.insert(tp) {
             continue;
         }
+        v.push(tp);
 
-        if let Some((e, t)) = query.get_single_mut().ok() {
-            let tp = project_iso(p);
-            let ptp = project_iso(&t.translation.xy());
-
-            commands
-                .entity(e)
-                .remove::<TilePath>()
-                .insert(Destination::new(
-                    TilePos(ptp.x as u32, ptp.y as u32),
-                    TilePos(tp.x as u32, tp.y as u32),
-                ));
+        stack.push(TilePos(tp.0 + 1, tp.1));
+        stack.push(TilePos(tp.0, tp.1 + 1));
+        if tp.0 > 0 {
+            stack.push(TilePos(tp.0 - 1, tp.1));
         }
+        if tp.1 > 0 {
+            stack.push(TilePos(tp.0, tp.1 - 1));
+        }
+    }
+    v
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set(tiles: &[(u32, u32)]) -> HashSet<TilePos> {
+        tiles.iter().map(|&(x, y)| TilePos(x, y)).collect()
+    }
+
+    #[test]
+    fn rectangles_accept_corners_in_any_order() {
+        let expected = set(&[(1, 0), (2, 0), (3, 0), (1, 1), (2, 1), (3, 1)]);
+        for (a, b) in [
+            ((1, 0), (3, 1)),
+            ((3, 1), (1, 0)),
+            ((3, 0), (1, 1)),
+            ((1, 1), (3, 0)),
+        ] {
+            let tiles = rectangle(TilePos(a.0, a.1), TilePos(b.0, b.1));
+            assert_eq!(tiles.len(), expected.len(), "{:?} to {:?}", a, b);
+            assert_eq!(tiles.into_iter().collect::<HashSet<_>>(), expected);
+        }
+        assert_eq!(rectangle(TilePos(2, 2), TilePos(2, 2)), [TilePos(2, 2)]);
+    }
+
+    #[test]
+    fn steep_lines_step_once_per_row() {
+        assert_eq!(
+            line(TilePos(0, 0), TilePos(1, 4)),
+            [
+                TilePos(0, 0),
+                TilePos(0, 1),
+                TilePos(1, 2),
+                TilePos(1, 3),
+                TilePos(1, 4)
+            ]
+        );
+
+        // Drawn backwards the line still visits every row once, in order
+        let back = line(TilePos(1, 4), TilePos(0, 0));
+        assert_eq!(back.first(), Some(&TilePos(1, 4)));
+        assert_eq!(back.last(), Some(&TilePos(0, 0)));
+        let rows: Vec<_> = back.iter().map(|tp| tp.1).collect();
+        assert_eq!(rows, [4, 3, 2, 1, 0]);
+    }
+
+    #[test]
+    fn diagonal_lines_move_both_ways_each_step() {
+        assert_eq!(
+            line(TilePos(0, 0), TilePos(3, 3)),
+            [TilePos(0, 0), TilePos(1, 1), TilePos(2, 2), TilePos(3, 3)]
+        );
+        assert_eq!(
+            line(TilePos(3, 0), TilePos(0, 3)),
+            [TilePos(3, 0), TilePos(2, 1), TilePos(1, 2), TilePos(0, 3)]
+        );
+        assert_eq!(line(TilePos(2, 5), TilePos(2, 5)), [TilePos(2, 5)]);
+    }
+
+    #[test]
+    fn flood_fill_stops_at_the_map_bounds() {
+        let tiles: HashMap<TilePos, u32> = rectangle(TilePos(0, 0), TilePos(5, 5))
+            .into_iter()
+            .map(|tp| (tp, 1))
+            .collect();
+        let filled = flood_fill(TilePos(1, 1), (3, 2), &tiles);
+        assert_eq!(filled.len(), 6);
+        assert_eq!(
+            filled.into_iter().collect::<HashSet<_>>(),
+            set(&[(0, 0), (1, 0), (2, 0), (0, 1), (1, 1), (2, 1)])
+        );
+    }
+
+    #[test]
+    fn flood_fill_stops_at_different_tiles() {
+        // 1 1 2 1
+        // 1 2 2 1
+        let tiles: HashMap<TilePos, u32> = [
+            ((0, 0), 1),
+            ((1, 0), 1),
+            ((2, 0), 2),
+            ((3, 0), 1),
+            ((0, 1), 1),
+            ((1, 1), 2),
+            ((2, 1), 2),
+            ((3, 1), 1),
+        ]
+        .into_iter()
+        .map(|((x, y), t)| (TilePos(x, y), t))
+        .collect();
+
+        let filled = flood_fill(TilePos(0, 0), (4, 2), &tiles);
+        assert_eq!(
+            filled.into_iter().collect::<HashSet<_>>(),
+            set(&[(0, 0), (1, 0), (0, 1)])
+        );
+        let filled = flood_fill(TilePos(2, 1), (4, 2), &tiles);
+        assert_eq!(
+            filled.into_iter().collect::<HashSet<_>>(),
+            set(&[(2, 0), (1, 1), (2, 1)])
+        );
+    }
+
+    #[test]
+    fn flood_fill_spreads_over_empty_cells() {
+        // A wall down the middle of an otherwise empty 3x3 map
+        let tiles: HashMap<TilePos, u32> = [(1, 0), (1, 1), (1, 2)]
+            .into_iter()
+            .map(|(x, y)| (TilePos(x, y), 1))
+            .collect();
+
+        let filled = flood_fill(TilePos(0, 1), (3, 3), &tiles);
+        assert_eq!(
+            filled.into_iter().collect::<HashSet<_>>(),
+            set(&[(0, 0), (0, 1), (0, 2)])
+        );
+
+        // Starting on the wall never leaks into the empty cells around it
+        let filled = flood_fill(TilePos(1, 0), (3, 3), &tiles);
+        assert_eq!(
+            filled.into_iter().collect::<HashSet<_>>(),
+            set(&[(1, 0), (1, 1), (1, 2)])
+        );
+        assert!(flood_fill(TilePos(5, 5), (3, 3), &tiles).is_empty());
     }
 }
@@ -3,10 +3,62 @@ use bevy::prelude::*;
 use bevy::render::render_resource::TextureUsages;
 use bevy::{asset::LoadState, render::render_resource::FilterMode};
 use bevy_ecs_tilemap::prelude::*;
-use bevy_tileset_map::prelude::Tileset;
+use bevy_tileset_map::prelude::{TileGroupId, Tileset};
 
 pub struct Plugin;
 
+/// The id of the map built by `load_map`
+pub const MAP_ID: u16 = 0;
+
+/// bevy_ecs_tilemap hangs each iso tile's image below its grid point, so maps
+/// are raised by this much to centre every diamond on `utils::iso_to_world`
+pub const MAP_LIFT: f32 = 4.;
+
+/// The layers of a map, bottom to top. The discriminant is the layer id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MapLayer {
+    Ground = 0,
+    Decoration = 1,
+    Obstacles = 2,
+    /// Highlights and other feedback drawn over the map
+    Overlay = 3,
+}
+
+impl MapLayer {
+    pub const ALL: [MapLayer; 4] = [
+        MapLayer::Ground,
+        MapLayer::Decoration,
+        MapLayer::Obstacles,
+        MapLayer::Overlay,
+    ];
+
+    pub fn id(self) -> u16 {
+        self as u16
+    }
+
+    pub fn from_id(id: u16) -> Option<Self> {
+        MapLayer::ALL.iter().copied().find(|l| l.id() == id)
+    }
+
+    /// Whether tiles on this layer affect where characters can walk
+    pub fn affects_walkability(self) -> bool {
+        self != MapLayer::Overlay
+    }
+}
+
+/// The size in tiles of the map built by `spawn_map`
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MapBounds {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl MapBounds {
+    pub fn contains(&self, pos: TilePos) -> bool {
+        pos.0 < self.width && pos.1 < self.height
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum AssetState {
     Initial,
@@ -18,6 +70,7 @@ impl BevyPlugin for Plugin {
     fn build(&self, app: &mut App) {
         app.add_state(AssetState::Initial)
             .init_resource::<Vec<Handle<Image>>>()
+            .init_resource::<MapBounds>()
             .add_system_set(SystemSet::on_enter(AssetState::Initial).with_system(setup_tiles))
             .add_system_set(SystemSet::on_update(AssetState::Loading).with_system(watch_load));
     }
@@ -31,10 +84,7 @@ fn setup_tiles(
     let imgs = vec![
         //
         // "tilesets/iso.png",
-        "characters/basic/basic_idle_01.png",
-        "characters/basic/basic_idle_02.png",
-        "characters/basic/basic_idle_03.png",
-        "characters/basic/basic_idle_04.png",
+        "characters/all.png",
     ];
     *images = imgs.iter().map(|s| asset_server.load(*s)).collect();
     asset_state
@@ -62,13 +112,51 @@ fn watch_load(
     }
 }
 
+/// Every tile group of `tileset` by id and name
+pub fn tile_groups(tileset: &Tileset) -> impl Iterator<Item = (TileGroupId, &str)> + '_ {
+    // Group ids in a tileset definition are contiguous from 0
+    (0..).map_while(move |id| tileset.get_tile_name(&id).map(|name| (id, name.as_str())))
+}
+
+/// The texture index of the first frame of a tile group
+pub fn group_texture_index(tileset: &Tileset, group: &str) -> Option<u16> {
+    tileset
+        .get_tile_index(group)
+        .map(|idx| *idx.base_index() as u16)
+}
+
+/// Builds the default map: a 64x64 ground layer and empty layers above it
 pub fn load_map(commands: &mut Commands, map_query: &mut MapQuery, tileset: &Tileset) {
+    spawn_map(commands, map_query, tileset, (64, 64), |layer, _| {
+        if layer == MapLayer::Ground {
+            Some(Tile {
+                texture_index: 1,
+                ..Default::default()
+            })
+        } else {
+            None
+        }
+    });
+}
+
+/// Builds a map of `size` tiles with every layer in `MapLayer`, filled in by
+/// `tile_at`
+pub fn spawn_map(
+    commands: &mut Commands,
+    map_query: &mut MapQuery,
+    tileset: &Tileset,
+    size: (u32, u32),
+    mut tile_at: impl FnMut(MapLayer, TilePos) -> Option<Tile>,
+) {
     let t_s = tileset.size();
     let map_entity = commands.spawn().id();
-    let mut map = Map::new(0u16, map_entity);
+    let mut map = Map::new(MAP_ID, map_entity);
 
-    let map_size = MapSize(1, 1);
     let chunk_size = ChunkSize(64, 64);
+    let map_size = MapSize(
+        (size.0 + chunk_size.0 - 1) / chunk_size.0,
+        (size.1 + chunk_size.1 - 1) / chunk_size.1,
+    );
     let tile_size = TileSize(18.0, 20.0);
     let texture_size = TextureSize(t_s.x, t_s.y);
     let grid_size = Vec2::new(16.0, 8.);
@@ -80,39 +168,37 @@ pub fn load_map(commands: &mut Commands, map_query: &mut MapQuery, tileset: &Til
     map_settings.grid_size = grid_size;
     map_settings.mesh_type = TilemapMeshType::Isometric(IsoType::Diamond);
 
-    for z in 0..1 {
+    for layer in MapLayer::ALL {
+        let z = layer.id();
         let (mut layer_builder, layer_entity) =
-            LayerBuilder::new(commands, map_settings.clone(), 0u16, z);
+            LayerBuilder::new(commands, map_settings.clone(), MAP_ID, z);
         map.add_layer(commands, z, layer_entity);
 
-        for x in 0..64 {
-            for y in 0..64 {
+        for x in 0..size.0 {
+            for y in 0..size.1 {
                 let position = TilePos(x, y);
-                let _ = layer_builder.set_tile(
-                    position,
-                    TileBundle {
-                        tile: Tile {
-                            texture_index: 1,
+                if let Some(tile) = tile_at(layer, position) {
+                    let _ = layer_builder.set_tile(
+                        position,
+                        TileBundle {
+                            tile,
                             ..Default::default()
                         },
-                        ..Default::default()
-                    },
-                );
+                    );
+                }
             }
         }
         map_query.build_layer(commands, layer_builder, tileset.texture().clone());
     }
 
-    // let size: Vec2 = Vec2::new(
-    //     (map_size.0 * chunk_size.0 * grid_size.x as u32) as f32 / 2.,
-    //     (map_size.1 * chunk_size.1 * grid_size.y as u32) as f32 / 2.,
-    // );
-    let t = Transform::from_xyz(0., 4., 0.);
-    // info!("T: {:#?}", t);
+    let t = Transform::from_xyz(0., MAP_LIFT, 0.);
     commands
         .entity(map_entity)
         .insert(map)
-        // .insert(Transform::default())
         .insert(t)
         .insert(GlobalTransform::default());
+    commands.insert_resource(MapBounds {
+        width: size.0,
+        height: size.1,
+    });
 }
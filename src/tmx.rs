@@ -0,0 +1,1084 @@
+use std::f32::consts::FRAC_PI_2;
+use std::fmt;
+use std::path::Path;
+
+use bevy::asset::{AssetLoader, AssetPath, BoxedFuture, LoadContext, LoadState, LoadedAsset};
+use bevy::prelude::Plugin as BevyPlugin;
+use bevy::prelude::*;
+use bevy::reflect::TypeUuid;
+use bevy::render::render_resource::FilterMode;
+use bevy::utils::HashMap;
+use bevy_ecs_tilemap::prelude::*;
+
+use crate::depth::DepthSort;
+use crate::pathfinding::{build_nav_grid, NavGrid};
+use crate::tiles::{MapBounds, MapLayer, MAP_LIFT};
+use crate::traversal::Traversal;
+use crate::utils::iso_to_world;
+
+/// Layers with this property set to `true` are spawned as depth-sorted
+/// sprites, so tall tiles can hide the characters behind them
+pub const DEPTH_SORT_PROPERTY: &str = "depth_sort";
+
+/// The Tiled example map shipped with the game
+pub const EXAMPLE_MAP: &str = "iso_tiled_example/iso_example.tmx";
+/// The example map's layers of tall tiles
+pub const EXAMPLE_DEPTH_SORTED_LAYERS: &[&str] = &["mountains"];
+/// The id of a map spawned from `TmxStartup`, clear of `tiles::MAP_ID`. Its
+/// tiles set the `MapBounds` and `NavGrid` in place of the editor's map.
+pub const TMX_MAP_ID: u16 = 1;
+
+const FLIPPED_HORIZONTALLY: u32 = 0x8000_0000;
+const FLIPPED_VERTICALLY: u32 = 0x4000_0000;
+const FLIPPED_DIAGONALLY: u32 = 0x2000_0000;
+const GID_MASK: u32 = !(FLIPPED_HORIZONTALLY | FLIPPED_VERTICALLY | FLIPPED_DIAGONALLY);
+
+pub struct Plugin;
+
+impl BevyPlugin for Plugin {
+    fn build(&self, app: &mut App) {
+        app.add_asset::<TmxMap>()
+            .init_asset_loader::<TmxLoader>()
+            .add_startup_system(spawn_startup_map)
+            .add_system(spawn_tmx_maps);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TmxOrientation {
+    Orthogonal,
+    Isometric,
+}
+
+/// A map loaded from a Tiled `.tmx` file
+#[derive(Debug, TypeUuid)]
+#[uuid = "0b3f7a8e-2c5d-4f1e-8a6b-9d4c2e1f7a30"]
+pub struct TmxMap {
+    pub orientation: TmxOrientation,
+    /// Size of the map in tiles
+    pub width: u32,
+    pub height: u32,
+    /// Size of the map grid in pixels
+    pub tile_width: u32,
+    pub tile_height: u32,
+    pub tilesets: Vec<TmxTileset>,
+    /// Tile layers, bottom to top
+    pub layers: Vec<TmxLayer>,
+    pub object_groups: Vec<TmxObjectGroup>,
+}
+
+#[derive(Debug)]
+pub struct TmxTileset {
+    pub first_gid: u32,
+    pub name: String,
+    pub tile_width: u32,
+    pub tile_height: u32,
+    pub tile_count: u32,
+    pub columns: u32,
+    /// Pixels between neighbouring tiles in the image
+    pub spacing: u32,
+    pub image: Handle<Image>,
+    pub image_width: u32,
+    pub image_height: u32,
+}
+
+impl TmxTileset {
+    fn contains(&self, gid: u32) -> bool {
+        gid >= self.first_gid && gid < self.first_gid + self.tile_count
+    }
+}
+
+#[derive(Debug)]
+pub struct TmxLayer {
+    pub name: String,
+    pub visible: bool,
+    pub properties: HashMap<String, String>,
+    /// Global tile ids, row by row, including Tiled's flip flags. `0` is empty.
+    pub gids: Vec<u32>,
+}
+
+#[derive(Debug)]
+pub struct TmxObjectGroup {
+    pub name: String,
+    pub objects: Vec<TmxObject>,
+}
+
+#[derive(Debug, Clone, Component)]
+pub struct TmxObject {
+    pub id: u32,
+    pub name: String,
+    pub kind: String,
+    /// Position in Tiled's pixel coordinates
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+    pub properties: HashMap<String, String>,
+}
+
+#[derive(Debug)]
+pub enum TmxError {
+    Xml(roxmltree::Error),
+    MissingElement(&'static str),
+    MissingAttribute {
+        element: &'static str,
+        attribute: &'static str,
+    },
+    InvalidAttribute {
+        element: &'static str,
+        attribute: &'static str,
+        value: String,
+    },
+    UnsupportedOrientation(String),
+    InfiniteMap,
+    ExternalTileset(String),
+    /// Tileset images with a border around the tiles can't be split into a
+    /// tilemap texture
+    UnsupportedMargin {
+        tileset: String,
+        margin: u32,
+    },
+    UnsupportedEncoding {
+        layer: String,
+        encoding: String,
+        compression: Option<String>,
+    },
+    InvalidTileData {
+        layer: String,
+        expected: usize,
+        found: usize,
+    },
+}
+
+impl fmt::Display for TmxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TmxError::Xml(e) => write!(f, "invalid TMX XML: {}", e),
+            TmxError::MissingElement(element) => write!(f, "missing <{}> element", element),
+            TmxError::MissingAttribute { element, attribute } => {
+                write!(f, "<{}> is missing the `{}` attribute", element, attribute)
+            }
+            TmxError::InvalidAttribute {
+                element,
+                attribute,
+                value,
+            } => write!(
+                f,
+                "<{}> has an invalid `{}` attribute: {:?}",
+                element, attribute, value
+            ),
+            TmxError::UnsupportedOrientation(o) => write!(
+                f,
+                "unsupported map orientation {:?}, expected orthogonal or isometric",
+                o
+            ),
+            TmxError::InfiniteMap => write!(f, "infinite maps are not supported"),
+            TmxError::ExternalTileset(source) => write!(
+                f,
+                "external tileset {:?} is not supported, embed it in the map",
+                source
+            ),
+            TmxError::UnsupportedMargin { tileset, margin } => write!(
+                f,
+                "tileset {:?} has a {}px margin, remove it in Tiled",
+                tileset, margin
+            ),
+            TmxError::UnsupportedEncoding {
+                layer,
+                encoding,
+                compression,
+            } => write!(
+                f,
+                "layer {:?} uses unsupported encoding {:?}{}, save it as CSV or XML",
+                layer,
+                encoding,
+                compression
+                    .as_ref()
+                    .map(|c| format!(" with {:?} compression", c))
+                    .unwrap_or_default()
+            ),
+            TmxError::InvalidTileData {
+                layer,
+                expected,
+                found,
+            } => write!(
+                f,
+                "layer {:?} has {} tiles, expected {}",
+                layer, found, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TmxError {}
+
+impl From<roxmltree::Error> for TmxError {
+    fn from(e: roxmltree::Error) -> Self {
+        TmxError::Xml(e)
+    }
+}
+
+fn attr<'a>(
+    node: roxmltree::Node<'a, '_>,
+    element: &'static str,
+    attribute: &'static str,
+) -> Result<&'a str, TmxError> {
+    node.attribute(attribute)
+        .ok_or(TmxError::MissingAttribute { element, attribute })
+}
+
+fn parse_attr<T: std::str::FromStr>(
+    node: roxmltree::Node,
+    element: &'static str,
+    attribute: &'static str,
+) -> Result<T, TmxError> {
+    let value = attr(node, element, attribute)?;
+    value.parse().map_err(|_| TmxError::InvalidAttribute {
+        element,
+        attribute,
+        value: value.to_string(),
+    })
+}
+
+fn parse_attr_or<T: std::str::FromStr>(
+    node: roxmltree::Node,
+    element: &'static str,
+    attribute: &'static str,
+    default: T,
+) -> Result<T, TmxError> {
+    match node.attribute(attribute) {
+        Some(_) => parse_attr(node, element, attribute),
+        None => Ok(default),
+    }
+}
+
+fn parse_properties(node: roxmltree::Node) -> HashMap<String, String> {
+    node.children()
+        .filter(|n| n.has_tag_name("properties"))
+        .flat_map(|n| n.children().filter(|p| p.has_tag_name("property")))
+        .filter_map(|p| {
+            let name = p.attribute("name")?;
+            let value = p
+                .attribute("value")
+                .or_else(|| p.text())
+                .unwrap_or_default();
+            Some((name.to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+/// Parses the TMX document, resolving tileset images relative to `dir`
+fn parse_tmx(
+    text: &str,
+    dir: &Path,
+    mut load_image: impl FnMut(AssetPath<'static>) -> Handle<Image>,
+) -> Result<TmxMap, TmxError> {
+    let doc = roxmltree::Document::parse(text)?;
+    let root = doc.root_element();
+    if !root.has_tag_name("map") {
+        return Err(TmxError::MissingElement("map"));
+    }
+
+    let orientation = match attr(root, "map", "orientation")? {
+        "orthogonal" => TmxOrientation::Orthogonal,
+        "isometric" => TmxOrientation::Isometric,
+        o => return Err(TmxError::UnsupportedOrientation(o.to_string())),
+    };
+    if parse_attr_or(root, "map", "infinite", 0u8)? != 0 {
+        return Err(TmxError::InfiniteMap);
+    }
+
+    let width: u32 = parse_attr(root, "map", "width")?;
+    let height: u32 = parse_attr(root, "map", "height")?;
+
+    let mut tilesets = Vec::new();
+    for node in root.children().filter(|n| n.has_tag_name("tileset")) {
+        if let Some(source) = node.attribute("source") {
+            return Err(TmxError::ExternalTileset(source.to_string()));
+        }
+
+        let image = node
+            .children()
+            .find(|n| n.has_tag_name("image"))
+            .ok_or(TmxError::MissingElement("image"))?;
+        let path = AssetPath::new(dir.join(attr(image, "image", "source")?), None);
+
+        let name = attr(node, "tileset", "name")?.to_string();
+        let margin = parse_attr_or(node, "tileset", "margin", 0)?;
+        if margin != 0 {
+            return Err(TmxError::UnsupportedMargin {
+                tileset: name,
+                margin,
+            });
+        }
+
+        tilesets.push(TmxTileset {
+            first_gid: parse_attr(node, "tileset", "firstgid")?,
+            name,
+            tile_width: parse_attr(node, "tileset", "tilewidth")?,
+            tile_height: parse_attr(node, "tileset", "tileheight")?,
+            tile_count: parse_attr(node, "tileset", "tilecount")?,
+            columns: parse_attr(node, "tileset", "columns")?,
+            spacing: parse_attr_or(node, "tileset", "spacing", 0)?,
+            image: load_image(path),
+            image_width: parse_attr(image, "image", "width")?,
+            image_height: parse_attr(image, "image", "height")?,
+        });
+    }
+
+    let mut layers = Vec::new();
+    let mut object_groups = Vec::new();
+    for node in root.children().filter(|n| n.is_element()) {
+        if node.has_tag_name("layer") {
+            let name = attr(node, "layer", "name")?.to_string();
+            let data = node
+                .children()
+                .find(|n| n.has_tag_name("data"))
+                .ok_or(TmxError::MissingElement("data"))?;
+
+            let gids: Vec<u32> = match (data.attribute("encoding"), data.attribute("compression")) {
+                (Some("csv"), None) => data
+                    .text()
+                    .unwrap_or_default()
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|s| !s.is_empty())
+                    .map(|s| {
+                        s.parse().map_err(|_| TmxError::InvalidAttribute {
+                            element: "data",
+                            attribute: "csv",
+                            value: s.to_string(),
+                        })
+                    })
+                    .collect::<Result<_, _>>()?,
+                (None, None) => data
+                    .children()
+                    .filter(|n| n.has_tag_name("tile"))
+                    .map(|n| parse_attr_or(n, "tile", "gid", 0))
+                    .collect::<Result<_, _>>()?,
+                (encoding, compression) => {
+                    return Err(TmxError::UnsupportedEncoding {
+                        layer: name,
+                        encoding: encoding.unwrap_or("xml").to_string(),
+                        compression: compression.map(str::to_string),
+                    })
+                }
+            };
+
+            let expected = (width * height) as usize;
+            if gids.len() != expected {
+                return Err(TmxError::InvalidTileData {
+                    layer: name,
+                    expected,
+                    found: gids.len(),
+                });
+            }
+
+            layers.push(TmxLayer {
+                visible: parse_attr_or(node, "layer", "visible", 1u8)? != 0,
+                properties: parse_properties(node),
+                name,
+                gids,
+            });
+        } else if node.has_tag_name("objectgroup") {
+            let objects = node
+                .children()
+                .filter(|n| n.has_tag_name("object"))
+                .map(|n| {
+                    Ok(TmxObject {
+                        id: parse_attr(n, "object", "id")?,
+                        name: n.attribute("name").unwrap_or_default().to_string(),
+                        kind: n.attribute("type").unwrap_or_default().to_string(),
+                        x: parse_attr(n, "object", "x")?,
+                        y: parse_attr(n, "object", "y")?,
+                        width: parse_attr_or(n, "object", "width", 0.)?,
+                        height: parse_attr_or(n, "object", "height", 0.)?,
+                        properties: parse_properties(n),
+                    })
+                })
+                .collect::<Result<_, TmxError>>()?;
+
+            object_groups.push(TmxObjectGroup {
+                name: node.attribute("name").unwrap_or_default().to_string(),
+                objects,
+            });
+        }
+    }
+
+    Ok(TmxMap {
+        orientation,
+        width,
+        height,
+        tile_width: parse_attr(root, "map", "tilewidth")?,
+        tile_height: parse_attr(root, "map", "tileheight")?,
+        tilesets,
+        layers,
+        object_groups,
+    })
+}
+
+#[derive(Default)]
+pub struct TmxLoader;
+
+impl AssetLoader for TmxLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let text = std::str::from_utf8(bytes)?;
+            let dir = load_context
+                .path()
+                .parent()
+                .map(Path::to_path_buf)
+                .unwrap_or_default();
+
+            let mut dependencies = Vec::new();
+            let map = parse_tmx(text, &dir, |path| {
+                dependencies.push(path.clone());
+                load_context.get_handle(path)
+            })?;
+
+            load_context.set_default_asset(LoadedAsset::new(map).with_dependencies(dependencies));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["tmx"]
+    }
+}
+
+/// Spawns a `TmxMap` as a tilemap on this entity once it and its tileset
+/// images have loaded
+#[derive(Debug, Clone, Component)]
+pub struct TmxMapSpawner {
+    pub handle: Handle<TmxMap>,
+    pub map_id: u16,
+    /// Names of layers to spawn as depth-sorted sprites, on top of those
+    /// with `DEPTH_SORT_PROPERTY` set
+    pub depth_sorted_layers: Vec<String>,
+}
+
+impl TmxMapSpawner {
+    /// Whether `layer` is spawned as depth-sorted sprites
+    pub fn sorts(&self, layer: &TmxLayer) -> bool {
+        layer.depth_sorted() || self.depth_sorted_layers.contains(&layer.name)
+    }
+}
+
+#[derive(Bundle)]
+pub struct TmxMapBundle {
+    pub spawner: TmxMapSpawner,
+    pub transform: Transform,
+    pub global_transform: GlobalTransform,
+}
+
+impl TmxMapBundle {
+    pub fn new(handle: Handle<TmxMap>, map_id: u16) -> Self {
+        TmxMapBundle {
+            spawner: TmxMapSpawner {
+                handle,
+                map_id,
+                depth_sorted_layers: Vec::new(),
+            },
+            transform: Transform::default(),
+            global_transform: GlobalTransform::default(),
+        }
+    }
+
+    pub fn with_depth_sorted_layers(mut self, layers: Vec<String>) -> Self {
+        self.spawner.depth_sorted_layers = layers;
+        self
+    }
+}
+
+/// A TMX map to show in place of the editor's map, from `--tmx [path]`. The
+/// path is relative to the assets folder and defaults to `EXAMPLE_MAP`.
+/// `--depth-sort a,b` names layers of tall tiles.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TmxStartup {
+    pub path: String,
+    pub depth_sorted_layers: Vec<String>,
+}
+
+impl TmxStartup {
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Option<Self> {
+        let args: Vec<String> = args.into_iter().collect();
+        let value_after = |flag: &str| {
+            let i = args.iter().position(|a| a == flag)?;
+            args.get(i + 1).filter(|a| !a.starts_with("--")).cloned()
+        };
+        if !args.iter().any(|a| a == "--tmx") {
+            return None;
+        }
+
+        let path = value_after("--tmx").unwrap_or_else(|| EXAMPLE_MAP.to_string());
+        let depth_sorted_layers = match value_after("--depth-sort") {
+            Some(layers) => layers.split(',').map(str::to_string).collect(),
+            None if path == EXAMPLE_MAP => EXAMPLE_DEPTH_SORTED_LAYERS
+                .iter()
+                .map(|l| l.to_string())
+                .collect(),
+            None => Vec::new(),
+        };
+        Some(TmxStartup {
+            path,
+            depth_sorted_layers,
+        })
+    }
+}
+
+fn spawn_startup_map(
+    startup: Option<Res<TmxStartup>>,
+    asset_server: Res<AssetServer>,
+    mut commands: Commands,
+) {
+    if let Some(startup) = startup {
+        let handle = asset_server.load(startup.path.as_str());
+        // Lifted like `tiles::spawn_map`, so picking and characters line up
+        commands.spawn_bundle(TmxMapBundle {
+            transform: Transform::from_xyz(0., MAP_LIFT, 0.),
+            ..TmxMapBundle::new(handle, TMX_MAP_ID)
+                .with_depth_sorted_layers(startup.depth_sorted_layers.clone())
+        });
+    }
+}
+
+/// Where a TMX object sits relative to its map
+fn object_position(map: &TmxMap, object: &TmxObject) -> Vec2 {
+    match map.orientation {
+        // Tiled measures both axes of iso objects in tile heights
+        TmxOrientation::Isometric => iso_to_world(&Vec2::new(
+            object.x / map.tile_height as f32,
+            object.y / map.tile_height as f32,
+        )),
+        TmxOrientation::Orthogonal => Vec2::new(
+            object.x,
+            map.height as f32 * map.tile_height as f32 - object.y,
+        ),
+    }
+}
+
+impl TmxLayer {
+    pub fn depth_sorted(&self) -> bool {
+        self.properties.get(DEPTH_SORT_PROPERTY).map(String::as_str) == Some("true")
+    }
+}
+
+/// Where tiles sit in map `tmx`, by Tiled column and row
+fn tile_position(tmx: &TmxMap, x: u32, y: u32) -> TilePos {
+    match tmx.orientation {
+        TmxOrientation::Isometric => TilePos(x, y),
+        // Tiled rows go down, bevy_ecs_tilemap rows go up
+        TmxOrientation::Orthogonal => TilePos(x, tmx.height - 1 - y),
+    }
+}
+
+/// The walkable tiles of `tmx`. Tiles on sorted layers are tall, so they
+/// block the tiles they stand on; the other layers are ground.
+pub fn tmx_nav_grid(tmx: &TmxMap, spawner: &TmxMapSpawner) -> NavGrid {
+    let tiles = tmx.layers.iter().flat_map(|layer| {
+        let (map_layer, traversal) = if spawner.sorts(layer) {
+            (MapLayer::Obstacles, Traversal::Blocked)
+        } else {
+            (MapLayer::Ground, Traversal::default())
+        };
+        layer
+            .gids
+            .iter()
+            .enumerate()
+            .filter(|(_, gid)| *gid & GID_MASK != 0)
+            .map(move |(i, _)| {
+                let (x, y) = (i as u32 % tmx.width, i as u32 / tmx.width);
+                (tile_position(tmx, x, y), map_layer, traversal)
+            })
+    });
+    build_nav_grid(tmx.width, tmx.height, tiles)
+}
+
+/// A tile of a depth-sorted TMX layer
+#[derive(Debug, Clone, Copy, Component)]
+pub struct TmxTileSprite {
+    pub layer: usize,
+    pub pos: TilePos,
+}
+
+fn spawn_tmx_maps(
+    query: Query<(Entity, &TmxMapSpawner, &Transform)>,
+    tmx_maps: Res<Assets<TmxMap>>,
+    asset_server: Res<AssetServer>,
+    mut texture_atlases: ResMut<Assets<TextureAtlas>>,
+    mut map_query: MapQuery,
+    mut commands: Commands,
+) {
+    for (map_entity, spawner, map_transform) in query.iter() {
+        let tmx = match tmx_maps.get(&spawner.handle) {
+            Some(tmx) => tmx,
+            None => continue,
+        };
+        let images = tmx.tilesets.iter().map(|t| t.image.id);
+        if asset_server.get_group_load_state(images) != LoadState::Loaded {
+            continue;
+        }
+
+        let mut map = Map::new(spawner.map_id, map_entity);
+        let chunk_size = ChunkSize(16, 16);
+        let map_size = MapSize(
+            (tmx.width + chunk_size.0 - 1) / chunk_size.0,
+            (tmx.height + chunk_size.1 - 1) / chunk_size.1,
+        );
+
+        let mut atlases = HashMap::default();
+
+        // bevy_ecs_tilemap layers hold one texture, so every TMX layer gets a
+        // layer per tileset it uses
+        for (li, layer) in tmx.layers.iter().enumerate() {
+            if spawner.sorts(layer) {
+                let sprites = spawn_tile_sprites(
+                    &mut commands,
+                    &mut texture_atlases,
+                    &mut atlases,
+                    tmx,
+                    li,
+                    map_transform,
+                );
+                commands.entity(map_entity).push_children(&sprites);
+                continue;
+            }
+
+            for (ti, tileset) in tmx.tilesets.iter().enumerate() {
+                if !layer.gids.iter().any(|g| tileset.contains(g & GID_MASK)) {
+                    continue;
+                }
+
+                let mut settings = LayerSettings::new(
+                    map_size,
+                    chunk_size,
+                    TileSize(tileset.tile_width as f32, tileset.tile_height as f32),
+                    TextureSize(tileset.image_width as f32, tileset.image_height as f32),
+                );
+                settings.filter = FilterMode::Nearest;
+                settings.cull = false;
+                settings.tile_spacing = Vec2::splat(tileset.spacing as f32);
+                settings.grid_size = Vec2::new(tmx.tile_width as f32, tmx.tile_height as f32);
+                settings.mesh_type = match tmx.orientation {
+                    TmxOrientation::Isometric => TilemapMeshType::Isometric(IsoType::Diamond),
+                    TmxOrientation::Orthogonal => TilemapMeshType::Square,
+                };
+
+                let layer_id = (li * tmx.tilesets.len() + ti) as u16;
+                let (mut layer_builder, layer_entity) = LayerBuilder::<TileBundle>::new(
+                    &mut commands,
+                    settings,
+                    spawner.map_id,
+                    layer_id,
+                );
+                map.add_layer(&mut commands, layer_id, layer_entity);
+
+                for (i, gid) in layer.gids.iter().enumerate() {
+                    if !tileset.contains(gid & GID_MASK) {
+                        continue;
+                    }
+
+                    let (x, y) = (i as u32 % tmx.width, i as u32 / tmx.width);
+                    let _ = layer_builder.set_tile(
+                        tile_position(tmx, x, y),
+                        TileBundle {
+                            tile: Tile {
+                                texture_index: ((gid & GID_MASK) - tileset.first_gid) as u16,
+                                flip_x: gid & FLIPPED_HORIZONTALLY != 0,
+                                flip_y: gid & FLIPPED_VERTICALLY != 0,
+                                flip_d: gid & FLIPPED_DIAGONALLY != 0,
+                                visible: layer.visible,
+                                ..Default::default()
+                            },
+                            ..Default::default()
+                        },
+                    );
+                }
+
+                map_query.build_layer(&mut commands, layer_builder, tileset.image.clone());
+            }
+        }
+
+        for group in tmx.object_groups.iter() {
+            for object in group.objects.iter() {
+                let position = object_position(tmx, object);
+                let object_entity = commands
+                    .spawn()
+                    .insert(object.clone())
+                    .insert(Name::new(format!("{}/{}", group.name, object.name)))
+                    .insert(Transform::from_translation(position.extend(0.)))
+                    .insert(GlobalTransform::default())
+                    .id();
+                commands.entity(map_entity).push_children(&[object_entity]);
+            }
+        }
+
+        if spawner.map_id == TMX_MAP_ID {
+            commands.insert_resource(MapBounds {
+                width: tmx.width,
+                height: tmx.height,
+            });
+            commands.insert_resource(tmx_nav_grid(tmx, spawner));
+        }
+
+        commands
+            .entity(map_entity)
+            .remove::<TmxMapSpawner>()
+            .insert(map);
+    }
+}
+
+/// The tile position, image centre and ground point of the tile in column
+/// `x` and Tiled row `y`, relative to the map. Images go where
+/// bevy_ecs_tilemap draws them, so sorted layers line up with the others.
+/// Iso tiles stand on the diamond at the top of their image, which
+/// `MAP_LIFT` centres on the tile's grid point.
+fn tile_sprite_placement(
+    tmx: &TmxMap,
+    tileset: &TmxTileset,
+    x: u32,
+    y: u32,
+) -> (TilePos, Vec2, Vec2) {
+    let tile = Vec2::new(tileset.tile_width as f32, tileset.tile_height as f32);
+    let grid = Vec2::new(tmx.tile_width as f32, tmx.tile_height as f32);
+    match tmx.orientation {
+        TmxOrientation::Isometric => {
+            let pos = TilePos(x, y);
+            // Images hang below their grid point, with the tile's ground
+            // diamond along their bottom edge
+            let grid_point = Vec2::new(
+                (x as f32 - y as f32) * grid.x / 2.,
+                -(x as f32 + y as f32) * grid.y / 2.,
+            );
+            let centre = grid_point - Vec2::new(0., tile.y / 2.);
+            let ground = grid_point - Vec2::new(0., grid.y / 2.);
+            (pos, centre, ground)
+        }
+        TmxOrientation::Orthogonal => {
+            // Square tiles fill their cell, and rows go up
+            let pos = TilePos(x, tmx.height - 1 - y);
+            let corner = Vec2::new(pos.0 as f32, pos.1 as f32) * tile;
+            (
+                pos,
+                corner + tile / 2.,
+                corner + Vec2::new(tile.x / 2., grid.y / 2.),
+            )
+        }
+    }
+}
+
+/// The sprite flips and rotation that draw a tile with Tiled's flip flags.
+/// A diagonal flip swaps the image's axes, which is a horizontal flip and a
+/// quarter turn; the flips after it then mirror the turned image.
+fn sprite_flip(gid: u32) -> (bool, bool, Quat) {
+    let flip_h = gid & FLIPPED_HORIZONTALLY != 0;
+    let flip_v = gid & FLIPPED_VERTICALLY != 0;
+    if gid & FLIPPED_DIAGONALLY != 0 {
+        (!flip_v, flip_h, Quat::from_rotation_z(FRAC_PI_2))
+    } else {
+        (flip_h, flip_v, Quat::IDENTITY)
+    }
+}
+
+/// Sorts a tile sprite by its ground point. Sprites are children of their
+/// map but sorted by their own translation, so the map's offset is added.
+fn tile_sprite_sort(centre: Vec2, ground: Vec2, map: &Transform) -> DepthSort {
+    DepthSort::with_foot_offset(map.translation.truncate() + ground - centre)
+}
+
+/// Spawns every tile of layer `li` as its own depth-sorted sprite
+fn spawn_tile_sprites(
+    commands: &mut Commands,
+    texture_atlases: &mut Assets<TextureAtlas>,
+    atlases: &mut HashMap<usize, Handle<TextureAtlas>>,
+    tmx: &TmxMap,
+    li: usize,
+    map: &Transform,
+) -> Vec<Entity> {
+    let layer = &tmx.layers[li];
+    let mut sprites = Vec::new();
+
+    for (i, gid) in layer.gids.iter().enumerate() {
+        let (ti, tileset) = match tmx
+            .tilesets
+            .iter()
+            .enumerate()
+            .find(|(_, t)| t.contains(gid & GID_MASK))
+        {
+            Some(found) => found,
+            None => continue,
+        };
+        let atlas = atlases
+            .entry(ti)
+            .or_insert_with(|| {
+                texture_atlases.add(TextureAtlas::from_grid_with_padding(
+                    tileset.image.clone(),
+                    Vec2::new(tileset.tile_width as f32, tileset.tile_height as f32),
+                    tileset.columns as usize,
+                    ((tileset.tile_count + tileset.columns - 1) / tileset.columns) as usize,
+                    Vec2::splat(tileset.spacing as f32),
+                ))
+            })
+            .clone();
+
+        let (x, y) = (i as u32 % tmx.width, i as u32 / tmx.width);
+        let (pos, centre, ground) = tile_sprite_placement(tmx, tileset, x, y);
+        let (flip_x, flip_y, rotation) = sprite_flip(*gid);
+        let sprite = commands
+            .spawn_bundle(SpriteSheetBundle {
+                texture_atlas: atlas,
+                sprite: TextureAtlasSprite {
+                    index: ((gid & GID_MASK) - tileset.first_gid) as usize,
+                    flip_x,
+                    flip_y,
+                    ..Default::default()
+                },
+                visibility: Visibility {
+                    is_visible: layer.visible,
+                },
+                transform: Transform::from_translation(centre.extend(0.)).with_rotation(rotation),
+                ..Default::default()
+            })
+            .insert(tile_sprite_sort(centre, ground, map))
+            .insert(TmxTileSprite { layer: li, pos })
+            .id();
+        sprites.push(sprite);
+    }
+    sprites
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::picking::tile_to_world;
+
+    fn parse(text: &str) -> Result<TmxMap, TmxError> {
+        parse_tmx(text, Path::new("maps"), |_| Handle::default())
+    }
+
+    #[test]
+    fn example_map_loads() {
+        let text = std::fs::read_to_string(Path::new("assets").join(EXAMPLE_MAP))
+            .expect("read example map");
+        let map = parse(&text).expect("parse example map");
+
+        assert_eq!(map.orientation, TmxOrientation::Isometric);
+        assert_eq!((map.width, map.height), (20, 20));
+        assert_eq!((map.tile_width, map.tile_height), (16, 8));
+
+        assert_eq!(map.tilesets.len(), 1);
+        let tileset = &map.tilesets[0];
+        assert_eq!(tileset.name, "iso_tileset");
+        assert_eq!(tileset.first_gid, 1);
+        assert_eq!((tileset.tile_width, tileset.tile_height), (18, 20));
+        assert_eq!(
+            (tileset.tile_count, tileset.columns, tileset.spacing),
+            (42, 7, 0)
+        );
+
+        let names: Vec<_> = map.layers.iter().map(|l| l.name.as_str()).collect();
+        assert_eq!(names, ["mountains", "base"]);
+        for layer in map.layers.iter() {
+            assert_eq!(layer.gids.len(), 400);
+            assert!(layer.visible);
+        }
+        let gid = |layer: usize, x: usize, y: usize| map.layers[layer].gids[y * 20 + x];
+        assert_eq!(gid(0, 2, 2), 34);
+        assert_eq!(gid(0, 4, 3), 32);
+        assert_eq!(gid(1, 6, 4), 30);
+        assert_eq!(gid(1, 15, 19), 0);
+        assert!(map.object_groups.is_empty());
+    }
+
+    /// Corners of the image bevy_ecs_tilemap 0.5 draws for a diamond iso
+    /// tile, following its chunk placement and `diamond_iso.wgsl`
+    fn tilemap_image_rect(tmx: &TmxMap, tileset: &TmxTileset, x: u32, y: u32) -> (Vec2, Vec2) {
+        let chunk = 16;
+        let grid = Vec2::new(tmx.tile_width as f32, tmx.tile_height as f32);
+        let tile = Vec2::new(tileset.tile_width as f32, tileset.tile_height as f32);
+        let project =
+            |x: f32, y: f32, size: Vec2| Vec2::new((x - y) * size.x / 2., -(x + y) * size.y / 2.);
+        let chunk_origin = project((x / chunk) as f32, (y / chunk) as f32, grid * chunk as f32);
+        let centre = chunk_origin + project((x % chunk) as f32, (y % chunk) as f32, grid);
+        (
+            Vec2::new(centre.x - tile.x / 2., centre.y - tile.y),
+            Vec2::new(centre.x + tile.x / 2., centre.y),
+        )
+    }
+
+    #[test]
+    fn sorted_tiles_line_up_with_tilemap_tiles() {
+        let text = std::fs::read_to_string(Path::new("assets").join(EXAMPLE_MAP))
+            .expect("read example map");
+        let map = parse(&text).expect("parse example map");
+        let tileset = &map.tilesets[0];
+
+        for (x, y) in [(0, 0), (2, 2), (17, 3), (19, 19)] {
+            let (pos, centre, ground) = tile_sprite_placement(&map, tileset, x, y);
+            assert_eq!(pos, TilePos(x, y));
+
+            let (start, end) = tilemap_image_rect(&map, tileset, x, y);
+            assert_eq!(centre, (start + end) / 2., "{} {}", x, y);
+            // The ground diamond fills the top of the image
+            assert_eq!(
+                ground,
+                Vec2::new(centre.x, end.y - map.tile_height as f32 / 2.)
+            );
+            assert_eq!(ground + Vec2::new(0., MAP_LIFT), tile_to_world(pos));
+        }
+    }
+
+    fn example_startup() -> (TmxMap, TmxMapSpawner) {
+        let text = std::fs::read_to_string(Path::new("assets").join(EXAMPLE_MAP))
+            .expect("read example map");
+        let map = parse(&text).expect("parse example map");
+        let startup = TmxStartup::from_args(["--tmx".to_string()]).expect("tmx startup");
+        let spawner = TmxMapBundle::new(Handle::default(), TMX_MAP_ID)
+            .with_depth_sorted_layers(startup.depth_sorted_layers)
+            .spawner;
+        (map, spawner)
+    }
+
+    #[test]
+    fn characters_sort_around_mountains() {
+        let (map, _) = example_startup();
+        let lifted = Transform::from_xyz(0., MAP_LIFT, 0.);
+        // A mountain of the example's `mountains` layer
+        assert_ne!(map.layers[0].gids[2 * 20 + 2], 0);
+        let (_, centre, ground) = tile_sprite_placement(&map, &map.tilesets[0], 2, 2);
+        let sort = tile_sprite_sort(centre, ground, &lifted);
+        // `depth_sort` sorts the sprite by its translation within the map
+        let mountain = DepthSort::depth(centre + sort.foot_offset);
+
+        let character = |x, y| DepthSort::depth(tile_to_world(TilePos(x, y)));
+        for (x, y) in [(3, 2), (2, 3), (3, 3)] {
+            assert!(character(x, y) > mountain, "{} {} is in front", x, y);
+        }
+        for (x, y) in [(1, 2), (2, 1), (1, 1)] {
+            assert!(character(x, y) < mountain, "{} {} is behind", x, y);
+        }
+    }
+
+    #[test]
+    fn the_example_map_is_walked_around_its_mountains() {
+        let (map, spawner) = example_startup();
+        let grid = tmx_nav_grid(&map, &spawner);
+        assert!(grid.contains(TilePos(19, 19)));
+        assert!(!grid.contains(TilePos(20, 0)));
+
+        let gid = |layer: usize, x: u32, y: u32| map.layers[layer].gids[(y * 20 + x) as usize];
+        for x in 0..20 {
+            for y in 0..20 {
+                let expected = gid(1, x, y) != 0 && gid(0, x, y) == 0;
+                let walkable = grid.step_cost(TilePos(x, y)).is_some();
+                assert_eq!(walkable, expected, "{} {}", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn the_example_sorts_its_mountains() {
+        let startup = TmxStartup::from_args(["--tmx".to_string()]).expect("tmx startup");
+        assert_eq!(startup.path, EXAMPLE_MAP);
+        assert_eq!(startup.depth_sorted_layers, ["mountains"]);
+
+        let args = ["--tmx", "maps/other.tmx", "--depth-sort", "trees,walls"];
+        let startup = TmxStartup::from_args(args.iter().map(|a| a.to_string())).expect("tmx");
+        assert_eq!(startup.depth_sorted_layers, ["trees", "walls"]);
+    }
+
+    const ONE_TILE: &str = r#"<map orientation="isometric" width="1" height="1"
+        tilewidth="16" tileheight="8">
+        <tileset firstgid="1" name="ts" tilewidth="16" tileheight="16" tilecount="4"
+            columns="2" SPACING>
+            <image source="ts.png" width="34" height="34"/>
+        </tileset>
+        <layer name="ground"><data encoding="csv">3</data></layer>
+    </map>"#;
+
+    #[test]
+    fn tileset_spacing_is_kept() {
+        let map = parse(&ONE_TILE.replace("SPACING", r#"spacing="2""#)).expect("parse");
+        assert_eq!(map.tilesets[0].spacing, 2);
+        assert_eq!(map.layers[0].gids, [3]);
+    }
+
+    #[test]
+    fn tileset_margin_is_an_error() {
+        match parse(&ONE_TILE.replace("SPACING", r#"margin="1""#)) {
+            Err(TmxError::UnsupportedMargin { tileset, margin }) => {
+                assert_eq!((tileset.as_str(), margin), ("ts", 1));
+            }
+            other => panic!("expected a margin error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn compressed_tile_data_is_an_error() {
+        for (encoding, compression) in [("base64", None), ("base64", Some("zlib"))] {
+            let data = match compression {
+                Some(c) => format!(r#"<data encoding="{}" compression="{}">"#, encoding, c),
+                None => format!(r#"<data encoding="{}">"#, encoding),
+            };
+            let text = ONE_TILE
+                .replace("SPACING", "")
+                .replace(r#"<data encoding="csv">3"#, &format!("{}AwAAAA==", data));
+            let err = parse(&text).expect_err("encoded data");
+            match &err {
+                TmxError::UnsupportedEncoding {
+                    layer,
+                    encoding: e,
+                    compression: c,
+                } => {
+                    assert_eq!((layer.as_str(), e.as_str()), ("ground", encoding));
+                    assert_eq!(c.as_deref(), compression);
+                }
+                other => panic!("expected an encoding error, got {:?}", other),
+            }
+            let expected = match compression {
+                Some(_) => {
+                    r#"layer "ground" uses unsupported encoding "base64" with "zlib" compression, save it as CSV or XML"#
+                }
+                None => {
+                    r#"layer "ground" uses unsupported encoding "base64", save it as CSV or XML"#
+                }
+            };
+            assert_eq!(err.to_string(), expected);
+        }
+    }
+
+    #[test]
+    fn sprite_flips_match_tiled() {
+        // Tiled flips diagonally, then horizontally, then vertically. Points
+        // are in world space, where y goes up.
+        let tiled = |gid: u32, p: Vec2| {
+            let mut p = p;
+            if gid & FLIPPED_DIAGONALLY != 0 {
+                p = Vec2::new(-p.y, -p.x);
+            }
+            if gid & FLIPPED_HORIZONTALLY != 0 {
+                p.x = -p.x;
+            }
+            if gid & FLIPPED_VERTICALLY != 0 {
+                p.y = -p.y;
+            }
+            p
+        };
+
+        let p = Vec2::new(3., 1.);
+        for flags in 0..8u32 {
+            let gid = (flags << 29) | 1;
+            let (flip_x, flip_y, rotation) = sprite_flip(gid);
+            let flipped = Vec2::new(
+                if flip_x { -p.x } else { p.x },
+                if flip_y { -p.y } else { p.y },
+            );
+            let drawn = (rotation * flipped.extend(0.)).truncate();
+            assert!((drawn - tiled(gid, p)).length() < 1e-5, "flags {:b}", flags);
+        }
+    }
+}
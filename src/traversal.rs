@@ -0,0 +1,107 @@
+use bevy::asset::{AssetLoader, BoxedFuture, LoadContext, LoadedAsset};
+use bevy::prelude::Plugin as BevyPlugin;
+use bevy::prelude::*;
+use bevy::reflect::TypeUuid;
+use bevy::utils::HashMap;
+use bevy_tileset_map::prelude::Tileset;
+use serde::Deserialize;
+
+use crate::tiles::group_texture_index;
+
+/// Cost of stepping onto a tile with a multiplier of `1.0`
+pub const BASE_STEP_COST: u32 = 10;
+
+pub struct Plugin;
+
+impl BevyPlugin for Plugin {
+    fn build(&self, app: &mut App) {
+        app.add_asset::<TilesetTraversal>()
+            .init_asset_loader::<TraversalLoader>()
+            .init_resource::<TerrainTraversal>()
+            .add_startup_system(load_traversal);
+    }
+}
+
+/// How a tile group may be crossed
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub enum Traversal {
+    Blocked,
+    /// Multiplier applied to `BASE_STEP_COST`
+    Cost(f32),
+}
+
+impl Default for Traversal {
+    fn default() -> Self {
+        Traversal::Cost(1.0)
+    }
+}
+
+impl Traversal {
+    /// The cost of stepping onto a tile, or `None` if it can't be entered.
+    ///
+    /// Multipliers below `1.0` are clamped so the pathfinding heuristic
+    /// never overestimates.
+    pub fn step_cost(&self) -> Option<u32> {
+        match *self {
+            Traversal::Blocked => None,
+            Traversal::Cost(m) => Some((BASE_STEP_COST as f32 * m.max(1.0)).round() as u32),
+        }
+    }
+}
+
+/// Traversal data for the tile groups of one tileset, loaded from a
+/// `*.traversal.ron` file next to it
+#[derive(Debug, Default, Deserialize, TypeUuid)]
+#[uuid = "5f6b7c0e-8f2a-4a8e-9a57-2d0f3f6f1c41"]
+pub struct TilesetTraversal {
+    /// Name of the tileset these groups belong to
+    pub tileset: String,
+    #[serde(default)]
+    pub groups: HashMap<String, Traversal>,
+}
+
+impl TilesetTraversal {
+    pub fn get(&self, group: &str) -> Traversal {
+        self.groups.get(group).copied().unwrap_or_default()
+    }
+
+    /// Builds a lookup from texture index to traversal for every group
+    /// declared in this file
+    pub fn by_texture_index(&self, tileset: &Tileset) -> HashMap<u16, Traversal> {
+        self.groups
+            .iter()
+            .filter_map(|(name, t)| group_texture_index(tileset, name).map(|idx| (idx, *t)))
+            .collect()
+    }
+}
+
+#[derive(Default)]
+pub struct TraversalLoader;
+
+impl AssetLoader for TraversalLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let traversal: TilesetTraversal = ron::de::from_bytes(bytes)?;
+            load_context.set_default_asset(LoadedAsset::new(traversal));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["traversal.ron"]
+    }
+}
+
+#[derive(Default)]
+pub struct TerrainTraversal {
+    /// This stores the handle to the terrain traversal data so it doesn't get unloaded
+    pub handle: Option<Handle<TilesetTraversal>>,
+}
+
+fn load_traversal(mut traversal: ResMut<TerrainTraversal>, asset_server: Res<AssetServer>) {
+    traversal.handle = Some(asset_server.load("tilesets/tileset.traversal.ron"));
+}